@@ -0,0 +1,93 @@
+use std::io::{Write, self};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use console::{Term, style};
+use futures::StreamExt;
+use futures::channel::mpsc;
+use indicatif::{ProgressBar, ProgressStyle};
+use structopt::StructOpt;
+use thiserror::Error;
+
+use tangara_lib::flash::backup::{self, BackupStatus, BackupTarget};
+
+use crate::device;
+
+#[derive(StructOpt)]
+pub struct BackupOpt {
+    /// Where to save the backup archive. Restore it with `tangara flash`
+    #[structopt(short, long, default_value = "tangara-backup.tra")]
+    output: PathBuf,
+    /// Only back up this partition, by its label in the device's partition
+    /// table. Can be given multiple times. Backs up the whole flash chip
+    /// by default
+    #[structopt(long, number_of_values = 1)]
+    partition: Vec<String>,
+    /// Baud rate to talk to the bootloader at. Slower rates are tried
+    /// automatically if this one doesn't work
+    #[structopt(long, default_value = "1000000")]
+    baud: u32,
+}
+
+#[derive(Error, Debug)]
+pub enum BackupError {
+    #[error(transparent)]
+    FindTangara(#[from] device::FindError),
+    #[error("backing up flash: {0}")]
+    Backup(#[from] backup::BackupError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub async fn run(args: BackupOpt, select: Option<&str>) -> Result<ExitCode, BackupError> {
+    let mut term = Term::stdout();
+
+    let target = if args.partition.is_empty() {
+        BackupTarget::Full
+    } else {
+        BackupTarget::Partitions(args.partition)
+    };
+
+    target.check()?;
+
+    let device = device::find(&mut term, select).await?;
+
+    writeln!(term, "Reading flash from device...")?;
+
+    let (tx, rx) = mpsc::channel(32);
+    let future = backup::backup(&device.params, target, &args.output, args.baud, tx);
+    let (result, ()) = futures::join!(future, show_progress(rx, args.baud));
+    result?;
+
+    writeln!(term, "Saved backup to {}", style(args.output.display()).green())?;
+
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn show_progress(mut progress: mpsc::Receiver<BackupStatus>, requested_baud: u32) {
+    let progress_bar = ProgressBar::no_length()
+        .with_style(ProgressStyle::with_template("{msg}\n{wide_bar} {bytes}/{total_bytes} {binary_bytes_per_sec}").unwrap());
+
+    while let Some(status) = progress.next().await {
+        match status {
+            BackupStatus::Connected { baud } => {
+                if baud != requested_baud {
+                    progress_bar.println(format!("Connected at {baud} baud"));
+                }
+            }
+            BackupStatus::Starting { total } => {
+                progress_bar.set_length(total);
+            }
+            BackupStatus::Progress { image, read, total } => {
+                progress_bar.set_message(image);
+                progress_bar.set_length(total);
+                progress_bar.set_position(read);
+            }
+            BackupStatus::Packing => {
+                progress_bar.set_message("Writing archive");
+            }
+        }
+    }
+
+    progress_bar.finish_and_clear();
+}
@@ -0,0 +1,98 @@
+use std::io::{Write, self};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use console::{Term, style};
+use structopt::StructOpt;
+use thiserror::Error;
+
+use tangara_lib::cache::{self, Cache};
+
+use crate::cmd::flash;
+use crate::device;
+
+#[derive(StructOpt)]
+pub struct CacheOpt {
+    #[structopt(subcommand)]
+    cmd: CacheCmd,
+}
+
+#[derive(StructOpt)]
+pub enum CacheCmd {
+    /// List cached firmware versions
+    List,
+    /// Add a firmware archive to the cache
+    Add {
+        path: PathBuf,
+    },
+    /// Remove a version from the cache
+    Remove {
+        version: String,
+    },
+    /// Remove all but the newest cached versions
+    Prune {
+        /// How many versions to keep
+        #[structopt(long, default_value = "3")]
+        keep: usize,
+    },
+    /// Flash a cached version, without downloading anything
+    Flash {
+        version: String,
+        #[structopt(flatten)]
+        flash: flash::FlashArgs,
+    },
+}
+
+#[derive(Error, Debug)]
+pub enum CacheError {
+    #[error(transparent)]
+    Cache(#[from] cache::CacheError),
+    #[error(transparent)]
+    FindTangara(#[from] device::FindError),
+    #[error(transparent)]
+    Flash(#[from] flash::FlashError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub async fn run(args: CacheOpt, select: Option<&str>) -> Result<ExitCode, CacheError> {
+    let mut term = Term::stdout();
+    let mut cache = Cache::open()?;
+
+    match args.cmd {
+        CacheCmd::List => {
+            let entries = cache.entries();
+
+            if entries.is_empty() {
+                writeln!(term, "No firmware cached in {}", cache.dir().display())?;
+            }
+
+            for entry in entries {
+                writeln!(term, "{:<12} {:>10}  {}",
+                    style(&entry.version).bold(),
+                    format!("{} KiB", entry.size / 1024),
+                    entry.source.as_deref().unwrap_or(""))?;
+            }
+        }
+        CacheCmd::Add { path } => {
+            let entry = cache.insert(&path, None)?;
+            writeln!(term, "Cached firmware version {}", style(&entry.version).bold())?;
+        }
+        CacheCmd::Remove { version } => {
+            let entry = cache.remove(&version)?;
+            writeln!(term, "Removed firmware version {}", style(&entry.version).bold())?;
+        }
+        CacheCmd::Prune { keep } => {
+            for entry in cache.prune(keep)? {
+                writeln!(term, "Removed firmware version {}", style(&entry.version).bold())?;
+            }
+        }
+        CacheCmd::Flash { version, flash } => {
+            let path = cache.checked_path(&version)?;
+            let device = device::find(&mut term, select).await?;
+            return Ok(flash::flash(&mut term, &path, device, &flash).await?);
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
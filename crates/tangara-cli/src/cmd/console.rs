@@ -26,10 +26,10 @@ pub enum ConsoleError {
     Io(#[from] io::Error),
 }
 
-pub async fn run() -> Result<ExitCode, ConsoleError> {
+pub async fn run(select: Option<&str>) -> Result<ExitCode, ConsoleError> {
     let mut term = Term::stdout();
 
-    let device = device::find(&mut term).await?;
+    let device = device::find(&mut term, select).await?;
 
     let mut serial = SerialStream::open(&build_serial(&device.params.serial))?;
     let mut terminal = AsyncTerminal::new(term);
@@ -0,0 +1,96 @@
+use std::io::{Write, self};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use console::{Term, style};
+use structopt::StructOpt;
+use thiserror::Error;
+
+use tangara_lib::device::coredump::{self, CrashReport};
+
+use crate::device;
+
+#[derive(StructOpt)]
+pub struct CoredumpOpt {
+    /// Where to save the raw coredump
+    #[structopt(short, long, default_value = "tangara-coredump.bin")]
+    output: PathBuf,
+    /// Print the panic reason, faulting task and backtrace
+    #[structopt(long)]
+    decode: bool,
+}
+
+#[derive(Error, Debug)]
+pub enum CoredumpError {
+    #[error(transparent)]
+    FindTangara(#[from] device::FindError),
+    #[error("reading coredump: {0}")]
+    Read(#[from] coredump::CoredumpError),
+    #[error("saving coredump: {0}")]
+    Save(#[source] io::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub async fn run(args: CoredumpOpt, select: Option<&str>) -> Result<ExitCode, CoredumpError> {
+    let mut term = Term::stdout();
+
+    let device = device::find(&mut term, select).await?;
+
+    writeln!(term, "Reading coredump from device...")?;
+
+    let Some(dump) = coredump::read(&device.params).await? else {
+        writeln!(term, "No coredump on device")?;
+        return Ok(ExitCode::SUCCESS);
+    };
+
+    dump.save(&args.output).map_err(CoredumpError::Save)?;
+
+    writeln!(term, "Saved {} byte coredump to {}",
+        dump.data().len(),
+        style(args.output.display()).green())?;
+
+    if args.decode {
+        match dump.decode() {
+            Ok(report) => print_report(&mut term, &report)?,
+            Err(error) => {
+                writeln!(term, "Can't decode coredump: {}",
+                    style(&format!("{error}")).yellow())?;
+            }
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn print_report(term: &mut Term, report: &CrashReport) -> io::Result<()> {
+    if let Some(reason) = &report.panic_reason {
+        writeln!(term, "{} {}", style("Panic:").bold(), reason)?;
+    }
+
+    if let Some(exception) = &report.exception {
+        writeln!(term, "{} {} ({}), address {:#010x}",
+            style("Exception:").bold(),
+            exception.cause_name(),
+            exception.cause,
+            exception.address)?;
+    }
+
+    if let Some(task) = &report.task {
+        writeln!(term, "{} {} (TCB {:#010x})",
+            style("Task:").bold(),
+            task.name.as_deref().unwrap_or("unknown"),
+            task.tcb)?;
+    }
+
+    if !report.backtrace.is_empty() {
+        let backtrace = report.backtrace.iter()
+            .map(|addr| format!("{addr:#010x}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        writeln!(term, "{} {}", style("Backtrace:").bold(), backtrace)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,50 @@
+use std::io::{Write, self};
+use std::process::ExitCode;
+
+use console::{Term, style};
+use structopt::StructOpt;
+use thiserror::Error;
+
+use tangara_lib::device::{DeviceState, Tangara};
+
+#[derive(StructOpt)]
+pub struct DevicesOpt {}
+
+#[derive(Error, Debug)]
+pub enum DevicesError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub async fn run() -> Result<ExitCode, DevicesError> {
+    let mut term = Term::stdout();
+
+    let devices = Tangara::find_with_bridges();
+
+    if devices.is_empty() {
+        writeln!(term, "No Tangara found, make sure it's plugged in and turned on")?;
+        return Ok(ExitCode::FAILURE);
+    }
+
+    for params in devices {
+        if params.is_bootloader() {
+            writeln!(term, "{}  {}",
+                style(&params.serial.port_name).green(),
+                style("usb serial bridge, pick with --device to reflash the ESP32 behind it").dim())?;
+            continue;
+        }
+
+        let state = match Tangara::classify(&params).await {
+            DeviceState::Normal => style("ok").green(),
+            DeviceState::Unresponsive => style("not responding").yellow(),
+            DeviceState::Bootloader => style("bootloader").yellow(),
+        };
+
+        writeln!(term, "{}  serial number {}  {}",
+            style(&params.serial.port_name).green(),
+            style(params.serial_number().unwrap_or("unknown")).bold(),
+            state)?;
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
@@ -0,0 +1,118 @@
+use std::io::{Write, self};
+use std::process::ExitCode;
+use std::str::FromStr;
+
+use console::{Term, style};
+use structopt::StructOpt;
+use thiserror::Error;
+
+use tangara_lib::flash::erase::{self, EraseTarget};
+
+use crate::device;
+use crate::util::{confirm, parse_u32};
+
+#[derive(StructOpt)]
+pub struct EraseOpt {
+    /// Erase the whole flash chip, including the firmware
+    #[structopt(long, required_unless_one = &["partition", "region"], conflicts_with_all = &["partition", "region"])]
+    chip: bool,
+    /// Erase a partition, by its label in the device's partition table
+    #[structopt(long, conflicts_with = "region")]
+    partition: Option<String>,
+    /// Erase a range of flash, as ADDR:SIZE in whole 4 KiB sectors
+    #[structopt(long)]
+    region: Option<RegionArg>,
+    /// Baud rate to talk to the bootloader at. Slower rates are tried
+    /// automatically if this one doesn't work
+    #[structopt(long, default_value = "1000000")]
+    baud: u32,
+}
+
+pub struct RegionArg {
+    addr: u32,
+    size: u32,
+}
+
+impl FromStr for RegionArg {
+    type Err = String;
+
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        let (addr, size) = arg.split_once(':')
+            .ok_or_else(|| format!("expected ADDR:SIZE, got {arg}"))?;
+
+        let addr = parse_u32(addr)
+            .map_err(|error| format!("bad address {addr}: {error}"))?;
+        let size = parse_u32(size)
+            .map_err(|error| format!("bad size {size}: {error}"))?;
+
+        if size == 0 {
+            return Err("region size must not be zero".to_owned());
+        }
+
+        if addr.checked_add(size).is_none() {
+            return Err(format!("region {addr:#x}, size {size:#x} runs past the end of flash"));
+        }
+
+        Ok(RegionArg { addr, size })
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum EraseError {
+    #[error(transparent)]
+    FindTangara(#[from] device::FindError),
+    #[error(transparent)]
+    Erase(#[from] erase::EraseError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub async fn run(args: EraseOpt, select: Option<&str>) -> Result<ExitCode, EraseError> {
+    let mut term = Term::stdout();
+
+    let (target, consequences) = match (args.chip, args.partition, args.region) {
+        (true, _, _) => {
+            let consequences = "The firmware and everything stored on the device's internal \
+                flash will be lost. Tangara won't start until firmware is flashed again."
+                .to_string();
+            (EraseTarget::Chip, consequences)
+        }
+        (false, Some(label), _) => {
+            let consequences = format!("Everything stored in the {label} partition will be lost. \
+                If it holds firmware, Tangara won't start until it's flashed again.");
+            (EraseTarget::Partition(label), consequences)
+        }
+        (false, None, Some(region)) => {
+            // checked when parsing, so the end can't overflow
+            let consequences = format!("Everything stored from {:#x} to {:#x} will be lost. \
+                If that overlaps the firmware, Tangara won't start until it's flashed again.",
+                region.addr, region.addr + region.size);
+            (EraseTarget::Region { addr: region.addr, size: region.size }, consequences)
+        }
+        (false, None, None) => unreachable!("structopt requires one of --chip, --partition or --region"),
+    };
+
+    let device = device::find(&mut term, select).await?;
+
+    writeln!(term, "{}", style(&consequences).yellow())?;
+    write!(term, "Erase {}? [y/n] ", style(describe(&target)).bold())?;
+    term.flush()?;
+
+    if !confirm(&mut term) {
+        return Ok(ExitCode::FAILURE);
+    }
+
+    writeln!(term, "Erasing...")?;
+    erase::erase(&device.params, target, args.baud).await?;
+    writeln!(term, "{}", style("Erase complete").green())?;
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn describe(target: &EraseTarget) -> String {
+    match target {
+        EraseTarget::Chip => "the whole flash chip".to_string(),
+        EraseTarget::Partition(label) => format!("partition {label}"),
+        EraseTarget::Region { addr, size } => format!("{size:#x} bytes at {addr:#x}"),
+    }
+}
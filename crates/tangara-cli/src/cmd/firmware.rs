@@ -0,0 +1,243 @@
+use std::io::{Write, self};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use console::{Term, style};
+use structopt::StructOpt;
+use thiserror::Error;
+
+use tangara_lib::firmware::{self, Firmware, Image, OpenError, ReadImageError};
+use tangara_lib::firmware::partition::PartitionTable;
+
+/// Size of a flash sector, the unit flash is erased and written in
+const BLOCK_SIZE: usize = 0x1000;
+
+#[derive(StructOpt)]
+pub struct FirmwareOpt {
+    #[structopt(subcommand)]
+    cmd: FirmwareCmd,
+}
+
+#[derive(StructOpt)]
+pub enum FirmwareCmd {
+    /// Show what's in a firmware archive
+    Inspect(InspectOpt),
+    /// Show which images differ between two firmware archives
+    Diff(DiffOpt),
+}
+
+#[derive(StructOpt)]
+pub struct InspectOpt {
+    image: PathBuf,
+}
+
+#[derive(StructOpt)]
+pub struct DiffOpt {
+    old: PathBuf,
+    new: PathBuf,
+}
+
+#[derive(Error, Debug)]
+pub enum FirmwareError {
+    #[error("opening firmware: {0}")]
+    OpenFirmware(#[from] OpenError),
+    #[error("reading image: {0}: {1}")]
+    ReadImage(String, #[source] ReadImageError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub async fn run(args: FirmwareOpt) -> Result<ExitCode, FirmwareError> {
+    match args.cmd {
+        FirmwareCmd::Inspect(args) => inspect(args),
+        FirmwareCmd::Diff(args) => diff(args),
+    }
+}
+
+fn inspect(args: InspectOpt) -> Result<ExitCode, FirmwareError> {
+    let mut term = Term::stdout();
+
+    let firmware = Firmware::open(&args.image)?;
+    let manifest = firmware.manifest();
+
+    writeln!(term, "{} {}", style("Version:").bold(), manifest.version)?;
+    writeln!(term, "{} {}", style("Manifest version:").bold(), manifest.manifest_version)?;
+
+    if let Some(revision) = &manifest.hardware_revision {
+        writeln!(term, "{} {}", style("Hardware revision:").bold(), revision)?;
+    }
+
+    if let Some(version) = &manifest.min_companion_version {
+        writeln!(term, "{} {}", style("Minimum companion version:").bold(), version)?;
+    }
+
+    if let Some(date) = &manifest.release_date {
+        writeln!(term, "{} {}", style("Release date:").bold(), date)?;
+    }
+
+    writeln!(term, "{} {}", style("Signature:").bold(), firmware.verify().describe())?;
+
+    if let Some(app) = firmware.app_descriptor() {
+        writeln!(term, "{} {} {}", style("Build date:").bold(), app.date, app.time)?;
+        writeln!(term, "{} {}", style("ESP-IDF version:").bold(), app.idf_version)?;
+    }
+
+    if let Some(flash_size) = firmware.flash_size() {
+        writeln!(term, "{} {}", style("Flash size:").bold(), format_size(flash_size))?;
+    }
+
+    if let Some(notes) = &manifest.release_notes {
+        writeln!(term)?;
+        writeln!(term, "{}", style("Release notes:").bold())?;
+        for line in notes.lines() {
+            writeln!(term, "  {line}")?;
+        }
+    }
+
+    writeln!(term)?;
+    writeln!(term, "{}", style("Images:").bold())?;
+
+    for image in firmware.images() {
+        let sha256 = image.checksum()
+            .map_err(|error| FirmwareError::ReadImage(image.name.clone(), error))?;
+
+        writeln!(term, "  {:#010x}  {:>10}  {:<24} {}{}",
+            image.addr,
+            format_size(image.size),
+            image.name,
+            firmware::hex(&sha256),
+            if image.optional { "  (optional)" } else { "" })?;
+    }
+
+    if let Some(table) = firmware.partition_table() {
+        writeln!(term)?;
+        writeln!(term, "{}", style("Partitions:").bold())?;
+        print_partitions(&mut term, table)?;
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn print_partitions(term: &mut Term, table: &PartitionTable) -> io::Result<()> {
+    for partition in &table.partitions {
+        writeln!(term, "  {:#010x}  {:>10}  {:<16} {}, {}",
+            partition.offset,
+            format_size(partition.size),
+            partition.label,
+            partition.type_name(),
+            partition.subtype_name())?;
+    }
+
+    Ok(())
+}
+
+fn diff(args: DiffOpt) -> Result<ExitCode, FirmwareError> {
+    let mut term = Term::stdout();
+
+    let old = Firmware::open(&args.old)?;
+    let new = Firmware::open(&args.new)?;
+
+    writeln!(term, "{} {} -> {}", style("Version:").bold(), old.version(), new.version())?;
+    writeln!(term)?;
+
+    for new_image in new.images() {
+        let name = &new_image.name;
+
+        let Some(old_image) = old.images().iter().find(|image| image.name == *name) else {
+            writeln!(term, "  {:<24} {} at {:#010x}, {}",
+                name,
+                style("added").green(),
+                new_image.addr,
+                format_size(new_image.size))?;
+            continue;
+        };
+
+        let old_data = read_image(old_image)?;
+        let new_data = read_image(new_image)?;
+        let changes = compare(&old_data, &new_data);
+
+        let mut notes = Vec::new();
+
+        if old_image.addr != new_image.addr {
+            notes.push(format!("moved {:#010x} -> {:#010x}", old_image.addr, new_image.addr));
+        }
+
+        if old_image.size != new_image.size {
+            notes.push(format!("{} -> {}", format_size(old_image.size), format_size(new_image.size)));
+        }
+
+        if changes.bytes == 0 {
+            if notes.is_empty() {
+                writeln!(term, "  {:<24} {}", name, style("unchanged").dim())?;
+            } else {
+                writeln!(term, "  {:<24} {}", name, notes.join(", "))?;
+            }
+            continue;
+        }
+
+        notes.push(format!("{} bytes differ in {} of {} blocks",
+            changes.bytes,
+            changes.blocks,
+            changes.total_blocks));
+
+        writeln!(term, "  {:<24} {} {}", name, style("changed").yellow(), notes.join(", "))?;
+    }
+
+    for old_image in old.images() {
+        if !new.images().iter().any(|image| image.name == old_image.name) {
+            writeln!(term, "  {:<24} {} from {:#010x}",
+                old_image.name,
+                style("removed").red(),
+                old_image.addr)?;
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn read_image(image: &Image) -> Result<Vec<u8>, FirmwareError> {
+    image.read().map_err(|error| FirmwareError::ReadImage(image.name.clone(), error))
+}
+
+struct Changes {
+    /// Bytes that differ, counting bytes past the end of the shorter
+    /// image as different
+    bytes: usize,
+    /// Flash blocks containing at least one changed byte
+    blocks: usize,
+    total_blocks: usize,
+}
+
+fn compare(old: &[u8], new: &[u8]) -> Changes {
+    let len = old.len().max(new.len());
+    let total_blocks = len.div_ceil(BLOCK_SIZE);
+
+    let mut bytes = 0;
+    let mut blocks = 0;
+
+    for block in 0..total_blocks {
+        let start = block * BLOCK_SIZE;
+        let end = (start + BLOCK_SIZE).min(len);
+
+        let changed = (start..end)
+            .filter(|offset| old.get(*offset) != new.get(*offset))
+            .count();
+
+        if changed > 0 {
+            bytes += changed;
+            blocks += 1;
+        }
+    }
+
+    Changes { bytes, blocks, total_blocks }
+}
+
+fn format_size(bytes: u32) -> String {
+    if bytes >= 1024 * 1024 && bytes.is_multiple_of(1024 * 1024) {
+        format!("{} MiB", bytes / (1024 * 1024))
+    } else if bytes >= 1024 {
+        format!("{:.1} KiB", f64::from(bytes) / 1024.0)
+    } else {
+        format!("{bytes} B")
+    }
+}
@@ -5,12 +5,16 @@ use std::sync::Arc;
 
 use console::{Term, style};
 use futures::StreamExt;
-use indicatif::ProgressBar;
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
 use structopt::StructOpt;
-use tangara_lib::flash::{FlashStatus, self};
+use tangara_lib::flash::{FlashOptions, FlashStatus, ImageOutcome, self};
+use tangara_lib::device::{DeviceState, Tangara};
+use tangara_lib::flash::journal::Journal;
+use tangara_lib::flash::preflight::PreflightReport;
 use thiserror::Error;
 
 use tangara_lib::firmware::Firmware;
+use tangara_lib::firmware::signature::Verification;
 
 use crate::device::{self, FoundDevice};
 use crate::util::confirm;
@@ -18,10 +22,55 @@ use crate::util::confirm;
 #[derive(StructOpt)]
 pub struct FlashOpt {
     image: PathBuf,
+    #[structopt(flatten)]
+    flash: FlashArgs,
 }
 
-pub async fn run(args: FlashOpt) -> Result<ExitCode, FlashError> {
-    match flash_confirm(args).await {
+/// Options shared by every command that flashes firmware
+#[derive(StructOpt)]
+pub struct FlashArgs {
+    /// Rewrite every image, even ones already on the device
+    #[structopt(long)]
+    full: bool,
+    /// Only rewrite the sectors that changed within each image
+    #[structopt(long, conflicts_with = "full")]
+    diff_sectors: bool,
+    /// Finish an interrupted flash of the same firmware, skipping images
+    /// that were already written
+    #[structopt(long)]
+    resume: bool,
+    /// Baud rate to flash at. Slower rates are tried automatically if this
+    /// one doesn't work
+    #[structopt(long, default_value = "1000000")]
+    baud: u32,
+    /// Only write this image from the archive, eg. tangara.bin. Can be
+    /// given multiple times
+    #[structopt(long, number_of_values = 1)]
+    only: Vec<String>,
+    /// Flash even if the device fails preflight checks, eg. its battery is
+    /// low or the firmware is for different hardware
+    #[structopt(long)]
+    ignore_preflight: bool,
+    /// Flash firmware that isn't signed by a trusted key
+    #[structopt(long)]
+    allow_untrusted: bool,
+}
+
+impl FlashArgs {
+    pub fn options(&self) -> FlashOptions {
+        FlashOptions {
+            differential: !self.full,
+            skip_unchanged_sectors: self.diff_sectors,
+            resume: self.resume,
+            baud: self.baud,
+            only: self.only.clone(),
+            force: self.ignore_preflight,
+        }
+    }
+}
+
+pub async fn run(args: FlashOpt, select: Option<&str>) -> Result<ExitCode, FlashError> {
+    match flash_confirm(args, select).await {
         // turn writeln! io errors into failure exits:
         Err(FlashError::Io(_)) => Ok(ExitCode::FAILURE),
         // pass thru all other results:
@@ -40,52 +89,217 @@ pub enum FlashError {
     Io(#[from] io::Error),
     #[error(transparent)]
     Flash(#[from] tangara_lib::flash::FlashError),
+    #[error("refusing to flash firmware: {0}")]
+    Signature(String),
 }
 
-async fn flash_confirm(args: FlashOpt) -> Result<ExitCode, FlashError> {
+async fn flash_confirm(args: FlashOpt, select: Option<&str>) -> Result<ExitCode, FlashError> {
     let mut term = Term::stdout();
-    let device = device::find(&mut term).await?;
-    flash(&mut term, &args.image, device).await
+    let device = device::find(&mut term, select).await?;
+    flash(&mut term, &args.image, device, &args.flash).await
 }
 
-pub async fn flash(term: &mut Term, firmware_path: &Path, device: FoundDevice) -> Result<ExitCode, FlashError> {
+pub async fn flash(term: &mut Term, firmware_path: &Path, device: FoundDevice, args: &FlashArgs) -> Result<ExitCode, FlashError> {
     let firmware = Firmware::open(&firmware_path).map(Arc::new)?;
 
+    let verification = firmware.verify();
+    let description = verification.describe();
+    match &verification {
+        Verification::Trusted { .. } => writeln!(term, "{}", style(&description).green())?,
+        _ => writeln!(term, "{}", style(&description).yellow())?,
+    }
+
+    if !verification.is_allowed() {
+        return Err(FlashError::Signature(description));
+    }
+
+    if verification.needs_confirmation() && !args.allow_untrusted {
+        writeln!(term, "Only firmware signed by a trusted key is flashed by default. \
+            If you trust where this firmware came from, pass {} to flash it.",
+            style("--allow-untrusted").bold())?;
+        return Err(FlashError::Signature(description));
+    }
+
+    if let Some(notes) = firmware.release_notes() {
+        writeln!(term, "{}\n{}\n", style("Release notes:").bold(), notes.trim())?;
+    }
+
     // show confirmation prompt
-    write!(term, "Flash version {} to device? [y/n] ",
-        style(firmware.version()).bold())?;
+    if args.only.is_empty() {
+        write!(term, "Flash version {} to device? [y/n] ",
+            style(firmware.version()).bold())?;
+    } else {
+        writeln!(term, "{}", style("Only the chosen images will be written. If the rest of the firmware on the device is from a different version, Tangara may not start.").yellow())?;
+        write!(term, "Flash {} from version {} to device? [y/n] ",
+            style(args.only.join(", ")).bold(),
+            style(firmware.version()).bold())?;
+    }
     term.flush()?;
 
     if !confirm(term) {
         return Ok(ExitCode::FAILURE)
     }
 
-    let progress_bar = ProgressBar::new(1);
+    let state = device.state;
+    let params = Arc::new(device.params);
+
+    if args.resume {
+        match Journal::load(&params) {
+            Ok(Some(journal)) if journal.matches(&firmware) => {
+                writeln!(term, "Resuming flash, {} images already written",
+                    journal.completed().len())?;
+            }
+            _ => writeln!(term, "No interrupted flash of this firmware to resume, flashing everything")?,
+        }
+    }
+
+    let progress_bar = ProgressBar::new(1)
+        .with_style(ProgressStyle::with_template("{msg}\n{wide_bar} {bytes}/{total_bytes} {prefix}").unwrap());
     progress_bar.set_message("Starting flash");
 
-    let (mut flash, task) = flash::setup(Arc::new(device.params), firmware);
+    // a running device can tell us about its battery and hardware first
+    let tangara = match state {
+        DeviceState::Normal => Tangara::open(&params).await.ok(),
+        _ => None,
+    };
+
+    let (mut flash, task) = match tangara {
+        Some(tangara) => tangara.setup_flash(firmware, args.options()).await,
+        None => flash::setup(params, firmware, args.options()),
+    };
     std::thread::spawn(move || task.run());
 
+    // on ctrl-c, stop at the next safe point rather than mid write. the
+    // flash thread can take a while to get there, so a second ctrl-c exits
+    // right away
+    let ctrl_c = tokio::spawn({
+        let cancel = flash.cancel.clone();
+        let progress_bar = progress_bar.clone();
+        async move {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                if cancel.is_cancelled() {
+                    progress_bar.abandon();
+                    std::process::exit(130);
+                }
+
+                progress_bar.println("Cancelling, press Ctrl-C again to quit now...");
+                cancel.cancel();
+            }
+        }
+    });
+
+    let mut image = String::new();
+    let mut summary = None;
+
     while let Some(progress) = flash.progress.next().await {
         match progress {
-            FlashStatus::StartingFlash => {}
-            FlashStatus::Image(image) => {
-                progress_bar.set_message(image);
-                progress_bar.set_position(0);
-                progress_bar.set_length(1);
+            FlashStatus::StartingFlash { total_bytes, .. } => {
+                progress_bar.set_length(total_bytes);
+            }
+            FlashStatus::Image { index, count, name, .. } => {
+                image = format!("[{}/{count}] {name}", index + 1);
+                progress_bar.set_message(image.clone());
             }
-            FlashStatus::Progress(written, total) => {
-                progress_bar.set_length(total as u64);
-                progress_bar.set_position(written as u64);
+            FlashStatus::Phase(phase) => {
+                progress_bar.set_message(format!("{image} ({phase})"));
+            }
+            FlashStatus::Connected { baud } => {
+                if baud != args.baud {
+                    progress_bar.println(format!("Connected at {baud} baud"));
+                }
+            }
+            FlashStatus::Preflight(report) => {
+                if let Some(description) = describe_preflight(&report) {
+                    progress_bar.println(description);
+                }
+
+                // otherwise the flash stops, and the problems are shown below
+                if !report.passed() && args.ignore_preflight {
+                    progress_bar.println(style(format!("Ignoring failed preflight checks: {}", report.summary())).yellow().to_string());
+                }
+            }
+            FlashStatus::Progress(progress) => {
+                progress_bar.set_length(progress.total);
+                progress_bar.set_position(progress.written);
+
+                if let (Some(throughput), Some(eta)) = (progress.throughput, progress.eta) {
+                    progress_bar.set_prefix(format!("{}/s, {} left",
+                        HumanBytes(throughput as u64),
+                        HumanDuration(eta)));
+                }
+            }
+            FlashStatus::Skipped(name) => {
+                progress_bar.println(format!("{name} already on device, skipping"));
+            }
+            FlashStatus::Retrying { image, attempt } => {
+                progress_bar.println(format!("Writing {image} failed, retrying (attempt {})", attempt + 1));
+            }
+            FlashStatus::Finished(finished) => {
+                summary = Some(finished);
             }
         }
     }
 
     progress_bar.finish_and_clear();
+    ctrl_c.abort();
+
+    if let Err(error) = flash.result.await.unwrap() {
+        if let flash::FlashError::Preflight(report) = &error {
+            for problem in &report.problems {
+                writeln!(term, "{}", style(problem).red())?;
+            }
+
+            writeln!(term, "{}", style("Run again with --ignore-preflight to flash anyway").yellow())?;
+            return Err(error.into());
+        }
+
+        if let flash::FlashError::Cancelled { .. } = &error {
+            writeln!(term, "{}", style("Tangara may not start until flashing is finished").red())?;
+        }
 
-    flash.result.await.unwrap()?;
+        writeln!(term, "{}", style("Run again with --resume to skip images already written").yellow())?;
+        return Err(error.into());
+    }
+
+    if let Some(summary) = summary {
+        for image in &summary.images {
+            let outcome = match image.outcome {
+                ImageOutcome::Written => "written",
+                ImageOutcome::Skipped => "skipped",
+            };
+
+            writeln!(term, "  {:<24} {:>10}  {outcome:<8} {:.1}s",
+                image.name,
+                HumanBytes(image.size).to_string(),
+                image.elapsed.as_secs_f64())?;
+        }
+
+        writeln!(term, "Wrote {} in {}",
+            HumanBytes(summary.bytes_written),
+            HumanDuration(summary.elapsed))?;
+    }
 
     writeln!(term, "{}", style("Flash success!").green())?;
 
     Ok(ExitCode::SUCCESS)
 }
+
+/// One line of what the preflight checks found out about the device
+fn describe_preflight(report: &PreflightReport) -> Option<String> {
+    let mut found = Vec::new();
+
+    if let Some(chip) = report.chip {
+        found.push(chip.to_string());
+    }
+    if let Some(size) = report.flash_size {
+        found.push(format!("{} flash", HumanBytes(size.into())));
+    }
+    if let Some(revision) = &report.hardware_revision {
+        found.push(format!("hardware {revision}"));
+    }
+    if let Some(percent) = report.battery_percent {
+        found.push(format!("battery {percent}%"));
+    }
+
+    (!found.is_empty()).then(|| format!("Device: {}", found.join(", ")))
+}
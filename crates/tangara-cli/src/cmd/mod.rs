@@ -1,3 +1,11 @@
+pub mod backup;
+pub mod cache;
 pub mod console;
+pub mod coredump;
+pub mod devices;
+pub mod erase;
+pub mod firmware;
 pub mod flash;
+pub mod pack;
+pub mod releases;
 pub mod update;
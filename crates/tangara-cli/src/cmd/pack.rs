@@ -0,0 +1,181 @@
+use std::io::{Write, self};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::str::FromStr;
+
+use console::{Term, style};
+use structopt::StructOpt;
+use thiserror::Error;
+
+use tangara_lib::firmware::Firmware;
+use tangara_lib::firmware::builder::{BuildError, Builder};
+use tangara_lib::firmware::signature::{self, Verification};
+
+use crate::util::parse_u32;
+
+#[derive(StructOpt)]
+pub struct PackOpt {
+    /// Path to a tangara-fw repository with built firmware images
+    #[structopt(long)]
+    fwrepo: Option<PathBuf>,
+    /// Image to pack, as ADDR:PATH. Can be given multiple times. With
+    /// --fwrepo, replaces the repository's image of the same file name
+    #[structopt(long, number_of_values = 1)]
+    image: Vec<ImageArg>,
+    /// Version to write to the archive, read from tangara.bin by default.
+    /// Must match tangara.bin if it's packed
+    #[structopt(long)]
+    version: Option<String>,
+    /// Hardware revision the firmware targets
+    #[structopt(long)]
+    hardware: Option<String>,
+    /// Oldest version of Tangara Companion able to flash this firmware
+    #[structopt(long)]
+    min_companion_version: Option<String>,
+    /// File containing release notes
+    #[structopt(long)]
+    release_notes: Option<PathBuf>,
+    /// Release date, as YYYY-MM-DD
+    #[structopt(long)]
+    release_date: Option<String>,
+    /// File holding an ed25519 secret key, as 64 hex digits, to sign the
+    /// archive with
+    #[structopt(long)]
+    sign_key: Option<PathBuf>,
+    /// Where to write the archive, defaults to the current directory
+    #[structopt(long, default_value = ".")]
+    outputdir: PathBuf,
+}
+
+pub struct ImageArg {
+    addr: u32,
+    path: PathBuf,
+}
+
+impl FromStr for ImageArg {
+    type Err = String;
+
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        let (addr, path) = arg.split_once(':')
+            .ok_or_else(|| format!("expected ADDR:PATH, got {arg}"))?;
+
+        let addr = parse_u32(addr)
+            .map_err(|error| format!("bad address {addr}: {error}"))?;
+
+        Ok(ImageArg { addr, path: PathBuf::from(path) })
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum PackError {
+    #[error("one of --fwrepo or --image is required")]
+    NoImages,
+    #[error("image path has no file name: {0}")]
+    ImageName(PathBuf),
+    #[error("reading release notes: {0}")]
+    ReleaseNotes(#[source] io::Error),
+    #[error("reading signing key: {0}")]
+    ReadSignKey(#[source] io::Error),
+    #[error("signing key is not 64 hex digits: {0}")]
+    BadSignKey(PathBuf),
+    #[error("packed archive has a bad signature: {0}")]
+    Signature(String),
+    #[error(transparent)]
+    Build(#[from] BuildError),
+    #[error("packed archive doesn't open: {0}")]
+    Verify(#[from] tangara_lib::firmware::OpenError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub async fn run(args: PackOpt) -> Result<ExitCode, PackError> {
+    let mut term = Term::stdout();
+
+    let mut builder = match &args.fwrepo {
+        Some(repo) => Builder::from_firmware_repo(repo)?,
+        None if args.image.is_empty() => return Err(PackError::NoImages),
+        None => Builder::new(),
+    };
+
+    for image in &args.image {
+        let Some(name) = image.path.file_name() else {
+            return Err(PackError::ImageName(image.path.clone()));
+        };
+
+        let name = name.to_string_lossy().into_owned();
+
+        if args.fwrepo.is_some() {
+            builder = builder.without_image(&name);
+        }
+
+        builder = builder.image_file(name, image.addr, &image.path)?;
+    }
+
+    if let Some(version) = args.version {
+        builder = builder.version(version);
+    }
+
+    if let Some(hardware) = args.hardware {
+        builder = builder.hardware_revision(hardware);
+    }
+
+    if let Some(version) = args.min_companion_version {
+        builder = builder.min_companion_version(version);
+    }
+
+    if let Some(path) = &args.release_notes {
+        let notes = std::fs::read_to_string(path).map_err(PackError::ReleaseNotes)?;
+        builder = builder.release_notes(notes);
+    }
+
+    if let Some(date) = args.release_date {
+        builder = builder.release_date(date);
+    }
+
+    if let Some(path) = &args.sign_key {
+        let key = std::fs::read_to_string(path).map_err(PackError::ReadSignKey)?;
+        let key = signature::parse_signing_key(&key)
+            .ok_or_else(|| PackError::BadSignKey(path.clone()))?;
+        builder = builder.sign_with(key);
+    }
+
+    writeln!(term, "Found firmware version: {}",
+        style(builder.resolve_version()?).bold())?;
+
+    let file_name = builder.file_name()?;
+    let path = args.outputdir.join(&file_name);
+
+    // build alongside the destination, and only move it into place once
+    // it's checked, so a bad archive is never left behind
+    let partial = args.outputdir.join(format!("{file_name}.partial"));
+
+    if let Err(error) = write_checked(&mut term, &builder, &partial, args.sign_key.is_some()) {
+        let _ = std::fs::remove_file(&partial);
+        return Err(error);
+    }
+
+    std::fs::rename(&partial, &path)?;
+
+    writeln!(term, "{}", style(format!("Created {}", path.display())).green())?;
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Writes the archive, then makes sure what we wrote is something we can
+/// flash.
+fn write_checked(term: &mut Term, builder: &Builder, path: &Path, signed: bool) -> Result<(), PackError> {
+    builder.write(path)?;
+
+    let firmware = Firmware::open(path)?;
+
+    if signed {
+        let verification = firmware.verify();
+        if let Verification::Invalid(reason) = verification {
+            return Err(PackError::Signature(reason));
+        }
+
+        writeln!(term, "{}", verification.describe())?;
+    }
+
+    Ok(())
+}
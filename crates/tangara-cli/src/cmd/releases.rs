@@ -0,0 +1,56 @@
+use std::io::{Write, self};
+use std::process::ExitCode;
+
+use console::{Term, style};
+use structopt::StructOpt;
+use thiserror::Error;
+
+use tangara_lib::release::{ReleaseCatalog, ReleaseError};
+
+use crate::cmd::update::ReleaseSourceOpt;
+
+#[derive(StructOpt)]
+pub struct ReleasesOpt {
+    /// Include prereleases
+    #[structopt(long)]
+    prerelease: bool,
+    #[structopt(flatten)]
+    source: ReleaseSourceOpt,
+}
+
+#[derive(Error, Debug)]
+pub enum ReleasesError {
+    #[error(transparent)]
+    Release(#[from] ReleaseError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub async fn run(args: ReleasesOpt) -> Result<ExitCode, ReleasesError> {
+    let mut term = Term::stdout();
+
+    let catalog = ReleaseCatalog::fetch(&args.source.url).await?;
+
+    for release in catalog.releases(args.prerelease) {
+        let date = release.published_at.as_deref()
+            .and_then(|date| date.get(..10))
+            .unwrap_or("");
+
+        let mut notes = Vec::new();
+
+        if release.is_prerelease() {
+            notes.push(style("prerelease").yellow().to_string());
+        }
+
+        if release.archive.is_none() {
+            notes.push(style("no firmware archive").dim().to_string());
+        }
+
+        writeln!(term, "{} {:<10}  {}",
+            style(format!("{:<16}", release.version)).bold(),
+            date,
+            notes.join(", "))?;
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
@@ -1,14 +1,19 @@
 use std::io::{Write, self};
+use std::path::PathBuf;
 use std::process::ExitCode;
 
 use console::{Term, style};
+use futures::StreamExt;
+use futures::channel::mpsc;
 use indicatif::ProgressBar;
 use semver::Version;
-use serde::Deserialize;
 use structopt::StructOpt;
 use tempfile::NamedTempFile;
 use thiserror::Error;
 
+use tangara_lib::cache::{self, Cache};
+use tangara_lib::release::{self, DownloadError, DownloadStatus, Release, ReleaseCatalog, ReleaseError};
+
 use crate::cmd::flash;
 use crate::device;
 
@@ -16,6 +21,26 @@ use crate::device;
 pub struct UpdateOpt {
     #[structopt(long)]
     force: bool,
+    #[structopt(flatten)]
+    flash: flash::FlashArgs,
+    /// Flash the newest cached firmware instead of checking for a release
+    #[structopt(long)]
+    offline: bool,
+    /// Flash this version instead of the latest
+    #[structopt(long)]
+    version: Option<Version>,
+    /// Consider prereleases when looking for the latest version
+    #[structopt(long)]
+    prerelease: bool,
+    #[structopt(flatten)]
+    source: ReleaseSourceOpt,
+}
+
+#[derive(StructOpt)]
+pub struct ReleaseSourceOpt {
+    /// API URL of the repository to fetch releases from
+    #[structopt(long = "release-source", env = "TANGARA_RELEASE_SOURCE", default_value = release::DEFAULT_SOURCE)]
+    pub url: String,
 }
 
 #[derive(Error, Debug)]
@@ -26,21 +51,65 @@ pub enum UpdateError {
     FindTangara(#[from] device::FindError),
     #[error("downloading firmware archive: {0}")]
     SaveFirmware(#[source] io::Error),
-    #[error("parsing latest release version: {0}")]
+    #[error("downloading firmware archive: {0}")]
+    Download(#[from] DownloadError),
+    #[error("parsing release version: {0}")]
     ParseVersion(#[from] semver::Error),
-    #[error("http error: {0}")]
-    Http(#[from] reqwest::Error),
-    #[error("can't find url for latest firmware archive")]
-    MissingAsset,
+    #[error(transparent)]
+    Release(#[from] ReleaseError),
+    #[error(transparent)]
+    Cache(#[from] cache::CacheError),
+    #[error("no firmware cached, run without --offline to download it")]
+    NothingCached,
     #[error(transparent)]
     Flash(#[from] flash::FlashError),
 }
 
-pub async fn run(args: UpdateOpt) -> Result<ExitCode, UpdateError> {
+pub async fn run(args: UpdateOpt, select: Option<&str>) -> Result<ExitCode, UpdateError> {
     let mut term = Term::stdout();
 
-    let device = device::find(&mut term).await?;
-    let release = query_latest_release(&mut term).await?;
+    let device = device::find(&mut term, select).await?;
+    let mut cache = open_cache(&mut term)?;
+
+    let release = if args.offline {
+        let cache = cache.as_ref().ok_or(UpdateError::NothingCached)?;
+
+        let entry = match &args.version {
+            Some(version) => cache.get(&version.to_string())
+                .ok_or_else(|| cache::CacheError::NotFound(version.to_string()))?,
+            None => cache.latest().ok_or(UpdateError::NothingCached)?,
+        };
+
+        SelectedRelease {
+            version: Version::parse(entry.version.trim_start_matches('v'))?,
+            source: ReleaseSource::Cached(cache.checked_path(&entry.version)?),
+        }
+    } else {
+        writeln!(term, "Querying releases from {}", style(&args.source.url).blue())?;
+        term.flush()?;
+
+        let catalog = ReleaseCatalog::fetch(&args.source.url).await?;
+
+        let release = match &args.version {
+            Some(version) => catalog.find(version)?,
+            None => catalog.latest(args.prerelease)?,
+        };
+
+        // no need to download it again if we already have it
+        let cached = cache.as_ref()
+            .and_then(|cache| cache.checked_path(&release.version.to_string()).ok());
+
+        let source = match cached {
+            Some(path) => ReleaseSource::Cached(path),
+            None => {
+                // fail before going any further if there's nothing to download
+                release.archive()?;
+                ReleaseSource::Download(release.clone())
+            }
+        };
+
+        SelectedRelease { version: release.version.clone(), source }
+    };
 
     match (&device.version, &release.version) {
         (Some(device), latest) if latest > device => {
@@ -49,6 +118,9 @@ pub async fn run(args: UpdateOpt) -> Result<ExitCode, UpdateError> {
         (None, _) => {
             // cannot determine device version (eg. reflashing after broken flash), proceed
         }
+        (Some(device), requested) if args.version.is_some() && requested != device => {
+            // asked for a specific version, even if it's older, proceed
+        }
         _ if args.force => {
             // forced update, proceed
         }
@@ -59,74 +131,88 @@ pub async fn run(args: UpdateOpt) -> Result<ExitCode, UpdateError> {
         }
     }
 
-    let firmware_file = download_firmware(&mut term, &release.url).await?;
-    flash::flash(&mut term, firmware_file.path(), device).await?;
-    Ok(ExitCode::SUCCESS)
-}
-
-async fn download_firmware(term: &mut Term, url: &str) -> Result<NamedTempFile, UpdateError> {
-    let mut file = NamedTempFile::with_suffix(".tra").map_err(UpdateError::SaveFirmware)?;
-
-    writeln!(term, "Downloading firmware from {}", style(url).blue())?;
-
-    let mut response = reqwest::get(url).await?;
-
-    let progress_bar = response.content_length()
-        .map(ProgressBar::new)
-        .unwrap_or_else(ProgressBar::no_length);
+    // keeps a downloaded archive around until we've flashed it, if it
+    // couldn't be cached
+    let mut temp_file = None;
 
-    while let Some(chunk) = response.chunk().await? {
-        progress_bar.inc(chunk.len() as u64);
-        file.write_all(&chunk).map_err(UpdateError::SaveFirmware)?;
-    }
-
-    progress_bar.finish_and_clear();
-
-    Ok(file)
-}
+    let firmware_path = match release.source {
+        ReleaseSource::Cached(path) => {
+            writeln!(term, "Using cached firmware {}", style(path.display()).blue())?;
+            path
+        }
+        ReleaseSource::Download(release) => {
+            let url = &release.archive()?.url;
+            writeln!(term, "Downloading firmware from {}", style(url).blue())?;
+
+            match &mut cache {
+                Some(cache) => {
+                    let (tx, rx) = mpsc::channel(32);
+                    let future = release::download_to_cache(&release, cache, tx);
+                    let (result, ()) = futures::join!(future, show_progress(rx));
+                    result?
+                }
+                None => {
+                    let mut file = NamedTempFile::with_suffix(".tra")
+                        .map_err(UpdateError::SaveFirmware)?;
+
+                    let (tx, rx) = mpsc::channel(32);
+                    let future = release::download(&release, &mut file, tx);
+                    let (result, ()) = futures::join!(future, show_progress(rx));
+                    result?;
+
+                    temp_file.insert(file).path().to_owned()
+                }
+            }
+        }
+    };
 
-struct LatestRelease {
-    version: Version,
-    url: String,
+    flash::flash(&mut term, &firmware_path, device, &args.flash).await?;
+    Ok(ExitCode::SUCCESS)
 }
 
-async fn query_latest_release(term: &mut Term) -> Result<LatestRelease, UpdateError> {
-    #[derive(Deserialize)]
-    struct Release {
-        name: String,
-        assets: Vec<ReleaseAsset>,
-    }
-
-    #[derive(Deserialize)]
-    struct ReleaseAsset {
-        name: String,
-        browser_download_url: String,
+/// Opens the firmware cache. Updating still works without a cache, it
+/// just can't save what it downloads.
+fn open_cache(term: &mut Term) -> Result<Option<Cache>, UpdateError> {
+    match Cache::open() {
+        Ok(cache) => Ok(Some(cache)),
+        Err(error) => {
+            writeln!(term, "{} {error}", style("Not caching firmware:").yellow())?;
+            Ok(None)
+        }
     }
+}
 
-    let url = "https://codeberg.org/api/v1/repos/cool-tech-zone/tangara-fw/releases/latest";
-
-    writeln!(term, "Querying latest release from {}",
-        style(url).blue())?;
-    term.flush()?;
-
-    let release: Release = reqwest::get(url).await?.json().await?;
-    let version = release_version(&release.name)?;
-
-    for asset in release.assets {
-        if asset.name.ends_with(".tra") {
-            return Ok(LatestRelease { version, url: asset.browser_download_url });
+async fn show_progress(mut progress: mpsc::Receiver<DownloadStatus>) {
+    let progress_bar = ProgressBar::no_length();
+
+    while let Some(status) = progress.next().await {
+        match status {
+            DownloadStatus::Progress { downloaded, total } => {
+                if let Some(total) = total {
+                    progress_bar.set_length(total);
+                }
+                progress_bar.set_position(downloaded);
+            }
+            DownloadStatus::Verifying => {
+                progress_bar.set_message("Verifying");
+            }
+            DownloadStatus::Unverified => {
+                progress_bar.println(format!("{} release publishes no checksums",
+                    style("Not verifying download:").yellow()));
+            }
         }
     }
 
-    Err(UpdateError::MissingAsset)
+    progress_bar.finish_and_clear();
 }
 
-fn release_version(name: &str) -> Result<Version, semver::Error> {
-    let version = if name.starts_with("v") {
-        &name[1..]
-    } else {
-        &name
-    };
+/// The release we're going to flash, and where to get it from
+struct SelectedRelease {
+    version: Version,
+    source: ReleaseSource,
+}
 
-    Version::parse(version)
+enum ReleaseSource {
+    Cached(PathBuf),
+    Download(Release),
 }
@@ -4,38 +4,61 @@ use console::{Term, style};
 use semver::Version;
 use thiserror::Error;
 
-use tangara_lib::device::{ConnectionParams, Tangara};
+use tangara_lib::device::{ConnectionParams, DeviceState, Tangara};
 
 #[derive(Error, Debug)]
 pub enum FindError {
     #[error(transparent)]
     FindTangara(#[from] tangara_lib::device::FindTangaraError),
+    #[error("no ESP32 bootloader answering at {0}: {1}")]
+    Probe(String, #[source] tangara_lib::flash::FlashError),
 }
 
 pub struct FoundDevice {
     pub params: ConnectionParams,
+    pub state: DeviceState,
     pub version: Option<Version>,
 }
 
-pub async fn find(term: &mut Term) -> Result<FoundDevice, FindError> {
-    let params = Tangara::find()?;
+pub async fn find(term: &mut Term, select: Option<&str>) -> Result<FoundDevice, FindError> {
+    let params = match select {
+        Some(selector) => Tangara::find_by(selector)?,
+        None => Tangara::find()?,
+    };
+
+    // only reachable by naming it, but it could still be anything
+    if params.is_bootloader() {
+        let chip = tangara_lib::flash::probe_bootloader(&params).await
+            .map_err(|error| FindError::Probe(params.serial.port_name.clone(), error))?;
+
+        let _ = writeln!(term, "Found {chip} in bootloader mode at {}, it can only be reflashed",
+            style(&params.serial.port_name).green());
 
-    let version = match tangara_version(&params).await {
+        return Ok(FoundDevice { params, state: DeviceState::Bootloader, version: None });
+    }
+
+    let (state, version) = match tangara_version(&params).await {
         Ok(version) => {
             let _ = writeln!(term, "Found Tangara at {}, current firmware version {}",
                 style(&params.serial.port_name).green(),
                 style(&version).bold());
-            Some(version)
+            (DeviceState::Normal, Some(version))
         }
-        Err(error) => {
+        Err(error @ VersionError::ParseVersion(_)) => {
             let _ = writeln!(term, "Found Tangara at {}, cannot retrieve current firmware information: {}",
                 style(&params.serial.port_name).green(),
                 style(&format!("{error}")).yellow());
-            None
+            (DeviceState::Normal, None)
+        }
+        Err(error) => {
+            let _ = writeln!(term, "Found Tangara at {}, but it is not responding: {}",
+                style(&params.serial.port_name).green(),
+                style(&format!("{error}")).yellow());
+            (DeviceState::Unresponsive, None)
         }
     };
 
-    Ok(FoundDevice { params, version })
+    Ok(FoundDevice { params, state, version })
 }
 
 #[derive(Debug, Error)]
@@ -11,24 +11,51 @@ mod util;
 
 #[derive(StructOpt)]
 pub struct Opt {
+    /// Serial number or port name of the Tangara to use
+    #[structopt(long, global = true)]
+    device: Option<String>,
     #[structopt(subcommand)]
     cmd: Cmd,
 }
 
 #[derive(StructOpt)]
 pub enum Cmd {
+    Backup(cmd::backup::BackupOpt),
+    Cache(cmd::cache::CacheOpt),
     Console(cmd::console::ConsoleOpt),
+    Coredump(cmd::coredump::CoredumpOpt),
+    Devices(cmd::devices::DevicesOpt),
+    Erase(cmd::erase::EraseOpt),
+    Firmware(cmd::firmware::FirmwareOpt),
     Flash(cmd::flash::FlashOpt),
+    Pack(cmd::pack::PackOpt),
+    Releases(cmd::releases::ReleasesOpt),
     Update(cmd::update::UpdateOpt),
 }
 
 #[derive(Error, Debug)]
 enum RunError {
+    #[error(transparent)]
+    Backup(#[from] cmd::backup::BackupError),
+    #[error(transparent)]
+    Cache(#[from] cmd::cache::CacheError),
     #[error(transparent)]
     Console(#[from] cmd::console::ConsoleError),
     #[error(transparent)]
+    Coredump(#[from] cmd::coredump::CoredumpError),
+    #[error(transparent)]
+    Devices(#[from] cmd::devices::DevicesError),
+    #[error(transparent)]
+    Erase(#[from] cmd::erase::EraseError),
+    #[error(transparent)]
+    Firmware(#[from] cmd::firmware::FirmwareError),
+    #[error(transparent)]
     Flash(#[from] cmd::flash::FlashError),
     #[error(transparent)]
+    Pack(#[from] cmd::pack::PackError),
+    #[error(transparent)]
+    Releases(#[from] cmd::releases::ReleasesError),
+    #[error(transparent)]
     Update(#[from] cmd::update::UpdateError),
 }
 
@@ -64,9 +91,19 @@ fn log_level() -> LevelFilter {
 }
 
 async fn run(opt: Opt) -> Result<ExitCode, RunError> {
+    let device = opt.device.as_deref();
+
     match opt.cmd {
-        Cmd::Console(_) => Ok(cmd::console::run().await?),
-        Cmd::Flash(args) => Ok(cmd::flash::run(args).await?),
-        Cmd::Update(args) => Ok(cmd::update::run(args).await?),
+        Cmd::Backup(args) => Ok(cmd::backup::run(args, device).await?),
+        Cmd::Cache(args) => Ok(cmd::cache::run(args, device).await?),
+        Cmd::Console(_) => Ok(cmd::console::run(device).await?),
+        Cmd::Coredump(args) => Ok(cmd::coredump::run(args, device).await?),
+        Cmd::Devices(_) => Ok(cmd::devices::run().await?),
+        Cmd::Erase(args) => Ok(cmd::erase::run(args, device).await?),
+        Cmd::Firmware(args) => Ok(cmd::firmware::run(args).await?),
+        Cmd::Flash(args) => Ok(cmd::flash::run(args, device).await?),
+        Cmd::Pack(args) => Ok(cmd::pack::run(args).await?),
+        Cmd::Releases(args) => Ok(cmd::releases::run(args).await?),
+        Cmd::Update(args) => Ok(cmd::update::run(args, device).await?),
     }
 }
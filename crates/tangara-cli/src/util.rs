@@ -1,4 +1,5 @@
 use std::io::Write;
+use std::num::ParseIntError;
 
 use console::Term;
 
@@ -14,3 +15,11 @@ pub fn confirm(term: &mut Term) -> bool {
         _ => false,
     }
 }
+
+/// Parses a number given in decimal, or in hex with a `0x` prefix.
+pub fn parse_u32(arg: &str) -> Result<u32, ParseIntError> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => arg.parse(),
+    }
+}
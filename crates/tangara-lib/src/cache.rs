@@ -0,0 +1,251 @@
+//! Local cache of firmware archives, so that each release only needs to be
+//! downloaded once and can be flashed again offline.
+//!
+//! Archives live in the user's cache directory alongside an `index.json`
+//! recording the version and SHA-256 of each one.
+
+use std::cmp::Ordering;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::firmware::{self, Firmware, OpenError};
+
+const CACHE_SUBDIR: &str = "tangara-companion/firmware";
+const INDEX_FILE: &str = "index.json";
+
+#[derive(Debug, Error)]
+pub enum CacheError {
+    #[error("no cache directory on this system")]
+    NoCacheDir,
+    #[error("firmware cache: {0}")]
+    Io(#[from] io::Error),
+    #[error("firmware cache index: {0}")]
+    Index(#[from] serde_json::Error),
+    #[error("firmware cache: {0}")]
+    Firmware(#[from] OpenError),
+    #[error("version {0} is not in the firmware cache")]
+    NotFound(String),
+    #[error("cached firmware {0} has been modified")]
+    Modified(String),
+    #[error("firmware version {0} is not a valid version number")]
+    BadVersion(String),
+    #[error("{0} is not a plain file name")]
+    BadFileName(String),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub version: String,
+    /// File name within the cache directory
+    pub file: String,
+    /// Hex SHA-256 of the archive file
+    pub sha256: String,
+    pub size: u64,
+    /// Where the archive was downloaded from, if it was
+    #[serde(default)]
+    pub source: Option<String>,
+    /// When the archive was added, in seconds since the Unix epoch
+    pub added: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Index {
+    entries: Vec<CacheEntry>,
+}
+
+pub struct Cache {
+    dir: PathBuf,
+    index: Index,
+}
+
+impl Cache {
+    /// Opens the cache in the user's cache directory, creating it if
+    /// necessary.
+    pub fn open() -> Result<Self, CacheError> {
+        let dir = dirs::cache_dir().ok_or(CacheError::NoCacheDir)?;
+        Self::open_at(dir.join(CACHE_SUBDIR))
+    }
+
+    pub fn open_at(dir: PathBuf) -> Result<Self, CacheError> {
+        fs::create_dir_all(&dir)?;
+
+        let index = match fs::read(dir.join(INDEX_FILE)) {
+            Ok(json) => serde_json::from_slice(&json)?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Index::default(),
+            Err(error) => { return Err(error.into()); }
+        };
+
+        Ok(Cache { dir, index })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Cached archives, newest version first.
+    pub fn entries(&self) -> Vec<&CacheEntry> {
+        let mut entries = self.index.entries.iter().collect::<Vec<_>>();
+        entries.sort_by(|a, b| compare_versions(&b.version, &a.version));
+        entries
+    }
+
+    pub fn get(&self, version: &str) -> Option<&CacheEntry> {
+        let version = version.trim_start_matches('v');
+        self.index.entries.iter()
+            .find(|entry| entry.version.trim_start_matches('v') == version)
+    }
+
+    pub fn latest(&self) -> Option<&CacheEntry> {
+        self.entries().into_iter().next()
+    }
+
+    pub fn path(&self, entry: &CacheEntry) -> PathBuf {
+        self.dir.join(&entry.file)
+    }
+
+    /// Path to a file directly inside the cache directory. Names come from
+    /// archives and servers we don't control, so anything that could point
+    /// elsewhere is refused.
+    pub fn file_path(&self, name: &str) -> Result<PathBuf, CacheError> {
+        if Path::new(name).file_name() != Some(name.as_ref()) {
+            return Err(CacheError::BadFileName(name.to_owned()));
+        }
+
+        Ok(self.dir.join(name))
+    }
+
+    /// Path to a cached archive, after checking it hasn't changed since it
+    /// was cached.
+    pub fn checked_path(&self, version: &str) -> Result<PathBuf, CacheError> {
+        let entry = self.get(version)
+            .ok_or_else(|| CacheError::NotFound(version.to_owned()))?;
+
+        let path = self.path(entry);
+
+        if hash_file(&path)? != entry.sha256 {
+            return Err(CacheError::Modified(entry.version.clone()));
+        }
+
+        Ok(path)
+    }
+
+    pub fn open_firmware(&self, version: &str) -> Result<Firmware, CacheError> {
+        Ok(Firmware::open(&self.checked_path(version)?)?)
+    }
+
+    /// Copies an archive into the cache, replacing any cached archive of
+    /// the same version. The archive is opened first to make sure it's
+    /// valid and to find its version.
+    pub fn insert(&mut self, path: &Path, source: Option<&str>) -> Result<CacheEntry, CacheError> {
+        let firmware = Firmware::open(path)?;
+        let version = firmware.version().to_owned();
+        drop(firmware);
+
+        // the version comes from the archive's manifest, so only use its
+        // parsed form in the file name
+        let parsed = Version::parse(version.trim_start_matches('v'))
+            .map_err(|_| CacheError::BadVersion(version.clone()))?;
+
+        let file = format!("tangarafw-v{parsed}.tra");
+        let dest = self.file_path(&file)?;
+
+        // copy to a temporary name first so a failed copy never leaves a
+        // truncated archive under a name the index points to
+        let partial = self.file_path(&format!("{file}.partial"))?;
+        fs::copy(path, &partial)?;
+        fs::rename(&partial, &dest)?;
+
+        let entry = CacheEntry {
+            version,
+            file,
+            sha256: hash_file(&dest)?,
+            size: fs::metadata(&dest)?.len(),
+            source: source.map(str::to_owned),
+            added: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default(),
+        };
+
+        self.index.entries.retain(|existing| existing.file != entry.file);
+        self.index.entries.push(entry.clone());
+        self.write_index()?;
+
+        Ok(entry)
+    }
+
+    pub fn remove(&mut self, version: &str) -> Result<CacheEntry, CacheError> {
+        let entry = self.get(version)
+            .cloned()
+            .ok_or_else(|| CacheError::NotFound(version.to_owned()))?;
+
+        self.index.entries.retain(|existing| existing.file != entry.file);
+        self.write_index()?;
+        remove_file(&self.path(&entry))?;
+
+        Ok(entry)
+    }
+
+    /// Removes all but the newest `keep` versions, along with any index
+    /// entries whose archive has gone missing. Returns what was removed.
+    pub fn prune(&mut self, keep: usize) -> Result<Vec<CacheEntry>, CacheError> {
+        let (kept, removed): (Vec<_>, Vec<_>) = self.entries()
+            .into_iter()
+            .cloned()
+            .enumerate()
+            .partition(|(index, entry)| *index < keep && self.path(entry).exists());
+
+        self.index.entries = kept.into_iter().map(|(_, entry)| entry).collect();
+        self.write_index()?;
+
+        let removed = removed.into_iter().map(|(_, entry)| entry).collect::<Vec<_>>();
+
+        for entry in &removed {
+            remove_file(&self.path(entry))?;
+        }
+
+        Ok(removed)
+    }
+
+    fn write_index(&self) -> Result<(), CacheError> {
+        let json = serde_json::to_vec_pretty(&self.index)?;
+
+        let path = self.dir.join(INDEX_FILE);
+        let partial = self.dir.join(format!("{INDEX_FILE}.partial"));
+        fs::write(&partial, json)?;
+        fs::rename(&partial, &path)?;
+
+        Ok(())
+    }
+}
+
+fn hash_file(path: &Path) -> Result<String, io::Error> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(firmware::hex(&hasher.finalize()))
+}
+
+fn remove_file(path: &Path) -> Result<(), io::Error> {
+    match fs::remove_file(path) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+/// Orders by semver where both versions parse, otherwise as strings.
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let parse = |version: &str| Version::parse(version.trim_start_matches('v')).ok();
+
+    match (parse(a), parse(b)) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        _ => a.cmp(b),
+    }
+}
@@ -1,4 +1,6 @@
 pub mod connection;
+pub mod coredump;
+pub mod hotplug;
 pub mod info;
 
 use std::sync::Arc;
@@ -7,13 +9,24 @@ use futures::channel::oneshot;
 use mio_serial::{SerialPortInfo, UsbPortInfo, SerialPortType};
 use thiserror::Error;
 
-use crate::{firmware, flash::{self, open_flash_connection, Flash, FlashTask}};
+use crate::{firmware, flash::{self, open_flash_connection, preflight, Flash, FlashOptions, FlashTask}};
 
 pub use connection::Connection;
 
 const USB_VID: u16 = 4617; // cool tech zone
 const USB_PID: u16 = 8212; // Tangara
 
+/// USB serial bridges a Tangara's ESP32 can be attached through for
+/// recovery, rather than through the SAMD. Plenty of other things use these
+/// too, so they're only used when picked by name, never automatically.
+const USB_SERIAL_BRIDGE_IDS: &[(u16, u16)] = &[
+    (0x10c4, 0xea60), // Silicon Labs CP210x
+    (0x1a86, 0x7523), // WCH CH340
+    (0x1a86, 0x55d4), // WCH CH9102
+    (0x0403, 0x6001), // FTDI FT232R
+    (0x0403, 0x6010), // FTDI FT2232
+];
+
 #[derive(Clone, Debug)]
 pub struct Tangara {
     connection: Connection,
@@ -26,12 +39,46 @@ pub struct ConnectionParams {
     pub usb: UsbPortInfo,
 }
 
+/// What state a device is in, which decides what we can do with it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceState {
+    /// Running firmware and answering on the console
+    Normal,
+    /// Enumerates as a Tangara but doesn't answer on the console, most
+    /// likely because its firmware is broken
+    Unresponsive,
+    /// Only reachable through the ESP32 ROM bootloader, so the only thing
+    /// to do is flash it
+    Bootloader,
+}
+
+impl ConnectionParams {
+    /// Whether this device was found as a USB serial bridge, rather than
+    /// as a Tangara. If it's a Tangara at all, it can only be reached
+    /// through the ESP32 ROM bootloader.
+    pub fn is_bootloader(&self) -> bool {
+        !is_tangara_usb(self.usb.vid, self.usb.pid)
+    }
+
+    pub fn serial_number(&self) -> Option<&str> {
+        self.usb.serial_number.as_deref()
+    }
+
+    /// Whether this device is identified by `selector`, which is either
+    /// its USB serial number or its serial port name.
+    pub fn matches(&self, selector: &str) -> bool {
+        self.serial_number() == Some(selector) || self.serial.port_name == selector
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum FindTangaraError {
     #[error("Error enumerating serial ports: {0}")]
     Port(#[from] mio_serial::Error),
     #[error("Can't find Tangara, make sure it's plugged in and turned on")]
     NoTangara,
+    #[error("Can't find Tangara with serial number or port {0}")]
+    NoMatchingTangara(String),
 }
 
 #[derive(Debug, Error)]
@@ -54,6 +101,10 @@ impl Tangara {
         })
     }
 
+    pub fn params(&self) -> &ConnectionParams {
+        &self.params
+    }
+
     pub fn serial_port_name(&self) -> &str {
         &self.params.serial.port_name
     }
@@ -71,34 +122,73 @@ impl Tangara {
     }
 
     pub fn find() -> Result<ConnectionParams, FindTangaraError> {
-        match find_serialport() {
-            Ok(Some(params)) => { return Ok(params); }
-            Ok(None) => {}
-            Err(error) => {
-                log::error!("error enumerating serial ports: {error}");
-            }
+        Self::find_all()
+            .into_iter()
+            .next()
+            .ok_or(FindTangaraError::NoTangara)
+    }
+
+    /// Finds the Tangara with the given USB serial number or serial port
+    /// name, for when there's more than one plugged in. This is also the
+    /// only way to pick a USB serial bridge.
+    pub fn find_by(selector: &str) -> Result<ConnectionParams, FindTangaraError> {
+        let devices = Self::find_with_bridges();
+
+        if devices.is_empty() {
+            return Err(FindTangaraError::NoTangara);
         }
 
-        #[cfg(target_os = "linux")]
-        match find_devtmpfs() {
-            Ok(Some(params)) => { return Ok(params); }
-            Ok(None) => {}
-            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+        devices
+            .into_iter()
+            .find(|params| params.matches(selector))
+            .ok_or_else(|| FindTangaraError::NoMatchingTangara(selector.to_owned()))
+    }
+
+    /// Every Tangara plugged in
+    pub fn find_all() -> Vec<ConnectionParams> {
+        find_ports(is_tangara_usb)
+    }
+
+    /// Every Tangara plugged in, followed by any USB serial bridges that
+    /// might have a Tangara's ESP32 on the other end. Bridges must only be
+    /// used when the user picks one.
+    pub fn find_with_bridges() -> Vec<ConnectionParams> {
+        find_ports(|vid, pid| is_tangara_usb(vid, pid) || is_bridge_usb(vid, pid))
+    }
+
+    /// Works out what state a device is in by trying to talk to it. Devices
+    /// found in bootloader mode aren't probed, as they have no console.
+    pub async fn classify(params: &ConnectionParams) -> DeviceState {
+        if params.is_bootloader() {
+            return DeviceState::Bootloader;
+        }
+
+        match Tangara::open(params).await {
+            Ok(tangara) => {
+                tangara.connection.disconnect().await;
+                DeviceState::Normal
+            }
             Err(error) => {
-                log::error!("error enumerating /dev/serial/by-id: {error}");
+                log::warn!("{} not responding: {error}", params.serial.port_name);
+                DeviceState::Unresponsive
             }
         }
-
-        Err(FindTangaraError::NoTangara)
     }
 
-    pub async fn setup_flash(&self, firmware: Arc<firmware::Firmware>) -> (Flash, FlashTask) {
+    pub async fn setup_flash(&self, firmware: Arc<firmware::Firmware>, options: FlashOptions)
+        -> (Flash, FlashTask)
+    {
         let params = self.params.clone();
 
+        // battery and hardware can only be checked while the firmware is
+        // still running
+        let report = preflight::check_console(&self.connection, &firmware).await;
+
         // disconnect before trying to  reopen the port for flash
         self.connection.disconnect().await;
 
-        flash::setup(params, firmware)
+        let (flash, task) = flash::setup(params, firmware, options);
+        (flash, task.with_preflight(report))
     }
 }
 
@@ -118,61 +208,197 @@ fn reset_blocking(port: &ConnectionParams) -> Result<(), ResetError> {
     Ok(())
 }
 
+/// Puts devices in the order [`Tangara::find_all`] returns them: devices
+/// running firmware before ones in the bootloader, then by port name.
+pub fn sort_devices(devices: &mut [ConnectionParams]) {
+    devices.sort_by(|a, b| {
+        a.is_bootloader().cmp(&b.is_bootloader())
+            .then_with(|| a.serial.port_name.cmp(&b.serial.port_name))
+    });
+}
+
+fn is_tangara_usb(vid: u16, pid: u16) -> bool {
+    vid == USB_VID && pid == USB_PID
+}
+
+fn is_bridge_usb(vid: u16, pid: u16) -> bool {
+    USB_SERIAL_BRIDGE_IDS.contains(&(vid, pid))
+}
+
+/// Finds serial ports whose USB identity `matches`
+fn find_ports(matches: impl Fn(u16, u16) -> bool) -> Vec<ConnectionParams> {
+    match find_serialport(&matches) {
+        Ok(devices) if !devices.is_empty() => { return devices; }
+        Ok(_) => {}
+        Err(error) => {
+            log::error!("error enumerating serial ports: {error}");
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    match find_devtmpfs(&matches) {
+        Ok(devices) => { return devices; }
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+        Err(error) => {
+            log::error!("error enumerating /dev/serial/by-id: {error}");
+        }
+    }
+
+    Vec::new()
+}
+
 /// Finds a Tangara using the serialport crate. Cross platform, but
 /// doesn't work under Flatpak as it relies on udev and Flatpak does
 /// not have great udev support.
-fn find_serialport() -> Result<Option<ConnectionParams>, mio_serial::Error> {
+fn find_serialport(matches: &impl Fn(u16, u16) -> bool) -> Result<Vec<ConnectionParams>, mio_serial::Error> {
+    let mut devices = Vec::new();
+
     for port in mio_serial::available_ports()? {
         if let SerialPortType::UsbPort(usb) = &port.port_type {
-            if usb.vid == USB_VID && usb.pid == USB_PID {
-                return Ok(Some(ConnectionParams {
+            if matches(usb.vid, usb.pid) {
+                devices.push(ConnectionParams {
                     serial: port.clone(),
                     usb: usb.clone(),
-                }));
+                });
             }
         }
     }
 
-    Ok(None)
+    sort_devices(&mut devices);
+
+    Ok(devices)
 }
 
 /// Fallback for when we're running under Flatpak
 #[cfg(target_os = "linux")]
-fn find_devtmpfs() -> Result<Option<ConnectionParams>, std::io::Error> {
+fn find_devtmpfs(matches: &impl Fn(u16, u16) -> bool) -> Result<Vec<ConnectionParams>, std::io::Error> {
+    let mut devices = Vec::new();
+
     for entry in std::fs::read_dir("/dev/serial/by-id")? {
-        let entry = entry?;
+        // links come and go during hotplug, so one going missing under us
+        // shouldn't stop us finding the rest
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(error) => {
+                log::debug!("reading /dev/serial/by-id: {error}");
+                continue;
+            }
+        };
+
         let name = entry.file_name();
 
         let Some(name) = name.to_str() else {
             continue;
         };
 
-        if !name.starts_with("usb-cool_tech_zone_Tangara_") {
+        // a Tangara can be recognised from its link name alone, so skip
+        // resolving links we already know we don't want. anything else has
+        // to be resolved to look it up in sysfs
+        let by_name = parse_by_id_name(name);
+
+        if by_name.as_ref().is_some_and(|usb_info| !matches(usb_info.vid, usb_info.pid)) {
             continue;
         }
 
-        let path = entry.path().canonicalize()?;
+        let path = match entry.path().canonicalize() {
+            Ok(path) => path,
+            Err(error) => {
+                log::debug!("resolving /dev/serial/by-id/{name}: {error}");
+                continue;
+            }
+        };
 
-        let Some(path) = path.to_str().map(str::to_owned) else {
+        // prefer the real attributes from sysfs, but it's not always
+        // readable, so fall back to what we can glean from the link name
+        let Some(usb_info) = read_sysfs_usb_info(&path).or(by_name) else {
             continue;
         };
 
-        let usb_info = UsbPortInfo {
-            vid: USB_VID,
-            pid: USB_PID,
-            serial_number: None,
-            manufacturer: None,
-            product: None,
+        if !matches(usb_info.vid, usb_info.pid) {
+            continue;
+        }
+
+        let Some(path) = path.to_str().map(str::to_owned) else {
+            continue;
         };
 
-        return Ok(Some(ConnectionParams {
+        devices.push(ConnectionParams {
             serial: SerialPortInfo {
                 port_name: path,
                 port_type: SerialPortType::UsbPort(usb_info.clone()),
             },
             usb: usb_info,
-        }));
+        });
     }
 
-    Ok(None)
+    // read_dir order is arbitrary, keep device order stable
+    sort_devices(&mut devices);
+
+    Ok(devices)
+}
+
+/// Parses USB metadata out of a udev /dev/serial/by-id link name, which
+/// look like `usb-cool_tech_zone_Tangara_<serial>-if00`. udev replaces
+/// spaces in the manufacturer and product strings with underscores.
+#[cfg(target_os = "linux")]
+fn parse_by_id_name(name: &str) -> Option<UsbPortInfo> {
+    const MANUFACTURER: &str = "cool_tech_zone";
+    const PRODUCT: &str = "Tangara";
+
+    let rest = name.strip_prefix("usb-")?
+        .strip_prefix(MANUFACTURER)?
+        .strip_prefix('_')?
+        .strip_prefix(PRODUCT)?;
+
+    // serial number is optional, and followed by the interface number
+    let serial = rest.rsplit_once("-if")
+        .map(|(serial, _)| serial)
+        .unwrap_or(rest)
+        .strip_prefix('_')
+        .filter(|serial| !serial.is_empty());
+
+    Some(UsbPortInfo {
+        vid: USB_VID,
+        pid: USB_PID,
+        serial_number: serial.map(str::to_owned),
+        manufacturer: Some(MANUFACTURER.replace('_', " ")),
+        product: Some(PRODUCT.to_owned()),
+    })
+}
+
+/// Reads USB metadata for a tty device from sysfs. The tty's `device` link
+/// points somewhere below the USB device: at the interface for CDC ACM
+/// devices, or at the usb-serial port under the interface for USB serial
+/// bridges. So walk up until we find the device's attributes.
+#[cfg(target_os = "linux")]
+fn read_sysfs_usb_info(tty_path: &std::path::Path) -> Option<UsbPortInfo> {
+    let tty_name = tty_path.file_name()?;
+
+    let device = std::path::Path::new("/sys/class/tty")
+        .join(tty_name)
+        .join("device")
+        .canonicalize()
+        .ok()?;
+
+    let usb_device = device.ancestors()
+        .take_while(|path| path.starts_with("/sys/devices"))
+        .find(|path| path.join("idVendor").is_file())?;
+
+    let attr = |name: &str| {
+        std::fs::read_to_string(usb_device.join(name))
+            .ok()
+            .map(|value| value.trim().to_owned())
+    };
+
+    let hex_attr = |name: &str| {
+        attr(name).and_then(|value| u16::from_str_radix(&value, 16).ok())
+    };
+
+    Some(UsbPortInfo {
+        vid: hex_attr("idVendor")?,
+        pid: hex_attr("idProduct")?,
+        serial_number: attr("serial"),
+        manufacturer: attr("manufacturer"),
+        product: attr("product"),
+    })
 }
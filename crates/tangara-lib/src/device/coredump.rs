@@ -0,0 +1,121 @@
+//! Retrieval of ESP-IDF core dumps from the `coredump` flash partition.
+//!
+//! When the firmware crashes, ESP-IDF writes a core dump into flash before
+//! rebooting. We read it back over the serial port with the flasher stub,
+//! and save it in the same raw format that `espcoredump.py` accepts.
+
+mod decode;
+
+use std::io;
+use std::path::Path;
+
+use espflash::flasher::Flasher;
+use futures::channel::oneshot;
+use thiserror::Error;
+
+use crate::device::ConnectionParams;
+use crate::firmware::partition::PartitionType;
+use crate::flash::{self, FlashError, ReadFlashError, ReadPartitionTableError};
+
+pub use decode::{CrashReport, DecodeError, Exception, Task};
+
+const PARTITION_SUBTYPE_COREDUMP: u8 = 0x03;
+
+/// Every core dump starts with a header whose first word is the total
+/// length of the dump, including the header and trailing checksum.
+const HEADER_SIZE: u32 = 24;
+
+#[derive(Debug, Error)]
+pub enum CoredumpError {
+    #[error(transparent)]
+    Connect(#[from] FlashError),
+    #[error(transparent)]
+    Read(#[from] ReadFlashError),
+    #[error(transparent)]
+    PartitionTable(#[from] ReadPartitionTableError),
+    #[error("no coredump partition in device partition table")]
+    NoPartition,
+    #[error("coredump length {0} bytes does not fit in partition")]
+    BadLength(u32),
+    #[error("coredump thread terminated unexpectedly")]
+    Canceled(#[from] oneshot::Canceled),
+}
+
+/// A raw core dump, exactly as ESP-IDF wrote it to flash.
+pub struct Coredump {
+    data: Vec<u8>,
+}
+
+impl Coredump {
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Core dump format version. The low 16 bits are the format version,
+    /// the high 16 bits identify the chip.
+    pub fn version(&self) -> u32 {
+        read_u32(&self.data, 4)
+    }
+
+    /// Writes the dump in ESP-IDF's raw core dump format, suitable for
+    /// `espcoredump.py --core-format raw`.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        std::fs::write(path, &self.data)
+    }
+
+    pub fn decode(&self) -> Result<CrashReport, DecodeError> {
+        decode::decode(&self.data)
+    }
+}
+
+/// Reads the core dump from the device, if there is one. This resets the
+/// device into its bootloader, so any open console connection to it must
+/// be disconnected first.
+pub async fn read(params: &ConnectionParams) -> Result<Option<Coredump>, CoredumpError> {
+    let (tx, rx) = oneshot::channel();
+    let params = params.clone();
+    std::thread::spawn(move || {
+        let _ = tx.send(read_blocking(&params));
+    });
+    rx.await?
+}
+
+fn read_blocking(params: &ConnectionParams) -> Result<Option<Coredump>, CoredumpError> {
+    let mut flasher = flash::connect_flasher(params)?;
+    let result = read_coredump(&mut flasher);
+
+    if let Err(error) = flasher.connection().reset() {
+        log::warn!("resetting device after reading coredump: {error}");
+    }
+
+    result
+}
+
+fn read_coredump(flasher: &mut Flasher) -> Result<Option<Coredump>, CoredumpError> {
+    let table = flash::read_partition_table(flasher)?;
+    let partition = table.find_type(PartitionType::Data, PARTITION_SUBTYPE_COREDUMP)
+        .ok_or(CoredumpError::NoPartition)?;
+    let (offset, size) = (partition.offset, partition.size);
+
+    let header = flash::read_flash(flasher, offset, HEADER_SIZE)?;
+    let length = read_u32(&header, 0);
+
+    // erased flash reads back as all ones
+    if length == 0xffffffff || length == 0 {
+        return Ok(None);
+    }
+
+    if length < HEADER_SIZE || length > size {
+        return Err(CoredumpError::BadLength(length));
+    }
+
+    let data = flash::read_flash(flasher, offset, length)?;
+    Ok(Some(Coredump { data }))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    data.get(offset..offset + 4)
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(u32::from_le_bytes)
+        .unwrap_or(0)
+}
@@ -0,0 +1,545 @@
+//! Decoder for ESP-IDF's ELF core dump format on Xtensa. This only pulls
+//! out enough to say what went wrong: the panic reason, the faulting task
+//! and a raw backtrace. Symbolizing addresses is left to other tools.
+
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::firmware;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+
+// offsets into the PR_STATUS note written for each task
+const PRSTATUS_PID: usize = 24;
+const PRSTATUS_SIZE: usize = 72;
+// xtensa_elf_reg_t: pc, ps, lbeg, lend, lcount, sar, windowstart,
+// windowbase, reserved[8 + 48], ar[64]
+const REGS_PC: usize = 0;
+const REGS_AR: usize = (8 + 56) * 4;
+
+// xtensa special register numbers used in the EXTRA_INFO note
+const REG_EXCCAUSE: u32 = 232;
+const REG_EXCVADDR: u32 = 238;
+
+// pcTaskName moved by a word between IDF versions
+const TCB_NAME_OFFSETS: [u32; 2] = [56, 52];
+const TCB_NAME_LEN: usize = 16;
+
+const MAX_BACKTRACE: usize = 64;
+
+#[derive(Debug, Error)]
+pub enum DecodeError {
+    #[error("unsupported coredump format version {0:#x}")]
+    UnsupportedFormat(u32),
+    #[error("coredump checksum error: {0:X} (should be {1:X})")]
+    BadCRC(u32, u32),
+    #[error("coredump SHA-256 mismatch: {0} (should be {1})")]
+    BadHash(String, String),
+    #[error("malformed coredump ELF: {0}")]
+    Malformed(&'static str),
+}
+
+#[derive(Debug)]
+pub struct CrashReport {
+    pub panic_reason: Option<String>,
+    pub exception: Option<Exception>,
+    pub task: Option<Task>,
+    pub backtrace: Vec<u32>,
+}
+
+#[derive(Debug)]
+pub struct Exception {
+    pub cause: u32,
+    pub address: u32,
+}
+
+impl Exception {
+    pub fn cause_name(&self) -> &'static str {
+        match self.cause {
+            0 => "IllegalInstruction",
+            1 => "Syscall",
+            2 => "InstructionFetchError",
+            3 => "LoadStoreError",
+            4 => "Level1Interrupt",
+            5 => "Alloca",
+            6 => "IntegerDivideByZero",
+            8 => "Privileged",
+            9 => "LoadStoreAlignment",
+            12 => "InstrPIFDataError",
+            13 => "LoadStorePIFDataError",
+            14 => "InstrPIFAddrError",
+            15 => "LoadStorePIFAddrError",
+            16 => "InstTLBMiss",
+            17 => "InstTLBMultiHit",
+            18 => "InstFetchPrivilege",
+            20 => "InstFetchProhibited",
+            24 => "LoadStoreTLBMiss",
+            25 => "LoadStoreTLBMultihit",
+            26 => "LoadStorePrivilege",
+            28 => "LoadProhibited",
+            29 => "StoreProhibited",
+            _ => "Unknown",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Task {
+    pub tcb: u32,
+    pub name: Option<String>,
+}
+
+pub fn decode(data: &[u8]) -> Result<CrashReport, DecodeError> {
+    let version = read_u32(data, 4).unwrap_or(0);
+    let format = (version >> 8) & 0xff;
+    let variant = version & 0xff;
+
+    // format 1 is ELF, format 0 is the legacy binary format
+    if format != 1 {
+        return Err(DecodeError::UnsupportedFormat(version));
+    }
+
+    // even variants are checksummed with CRC32, odd ones with SHA256
+    let checksum_size = if variant & 1 == 0 { 4 } else { 32 };
+
+    let body = data.len().checked_sub(checksum_size)
+        .map(|len| &data[..len])
+        .ok_or(DecodeError::Malformed("truncated"))?;
+
+    let checksum = &data[body.len()..];
+
+    if checksum_size == 4 {
+        let expected = read_u32(data, body.len()).unwrap_or(0);
+        let crc32 = crc32fast::hash(body);
+        if crc32 != expected {
+            return Err(DecodeError::BadCRC(crc32, expected));
+        }
+    } else {
+        let sha256 = Sha256::digest(body);
+        if sha256.as_slice() != checksum {
+            return Err(DecodeError::BadHash(firmware::hex(&sha256), firmware::hex(checksum)));
+        }
+    }
+
+    // the header grew a word in later versions, so find the ELF by magic
+    let elf = [20, 24].into_iter()
+        .filter_map(|offset| body.get(offset..))
+        .find(|elf| elf.starts_with(ELF_MAGIC))
+        .ok_or(DecodeError::Malformed("no ELF header"))?;
+
+    let elf = Elf::parse(elf)?;
+    Ok(elf.report())
+}
+
+struct Elf<'a> {
+    notes: Vec<Note<'a>>,
+    segments: Vec<(u32, &'a [u8])>,
+}
+
+struct Note<'a> {
+    name: &'a [u8],
+    kind: u32,
+    desc: &'a [u8],
+}
+
+struct TaskRegs {
+    tcb: u32,
+    pc: u32,
+    a0: u32,
+    a1: u32,
+}
+
+impl<'a> Elf<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, DecodeError> {
+        // only 32 bit little endian, which is all the ESP32 writes
+        if data.get(4) != Some(&1) || data.get(5) != Some(&1) {
+            return Err(DecodeError::Malformed("not a 32 bit little endian ELF"));
+        }
+
+        let phoff = read_u32(data, 28).ok_or(DecodeError::Malformed("truncated header"))?;
+        let phentsize = read_u16(data, 42).ok_or(DecodeError::Malformed("truncated header"))?;
+        let phnum = read_u16(data, 44).ok_or(DecodeError::Malformed("truncated header"))?;
+
+        let mut notes = Vec::new();
+        let mut segments = Vec::new();
+
+        for index in 0..usize::from(phnum) {
+            let ph = phoff as usize + index * usize::from(phentsize);
+            let (Some(kind), Some(offset), Some(vaddr), Some(size)) = (
+                read_u32(data, ph),
+                read_u32(data, ph + 4),
+                read_u32(data, ph + 8),
+                read_u32(data, ph + 16),
+            ) else {
+                return Err(DecodeError::Malformed("truncated program header"));
+            };
+
+            let contents = data.get(offset as usize..(offset as usize + size as usize))
+                .ok_or(DecodeError::Malformed("segment out of bounds"))?;
+
+            match kind {
+                PT_LOAD => segments.push((vaddr, contents)),
+                PT_NOTE => parse_notes(contents, &mut notes)?,
+                _ => {}
+            }
+        }
+
+        Ok(Elf { notes, segments })
+    }
+
+    fn note(&self, name: &[u8]) -> Option<&Note<'a>> {
+        self.notes.iter().find(|note| note.name == name)
+    }
+
+    fn read_mem(&self, addr: u32) -> Option<u32> {
+        self.segments.iter().find_map(|(vaddr, contents)| {
+            read_u32(contents, addr.checked_sub(*vaddr)? as usize)
+        })
+    }
+
+    fn read_bytes(&self, addr: u32, len: usize) -> Option<&'a [u8]> {
+        self.segments.iter().find_map(|(vaddr, contents)| {
+            let offset = addr.checked_sub(*vaddr)? as usize;
+            contents.get(offset..offset + len)
+        })
+    }
+
+    fn tasks(&self) -> impl Iterator<Item = TaskRegs> + '_ {
+        self.notes.iter()
+            .filter(|note| note.name == b"CORE" && note.kind == NT_PRSTATUS)
+            .filter_map(|note| {
+                let regs = PRSTATUS_SIZE;
+                Some(TaskRegs {
+                    tcb: read_u32(note.desc, PRSTATUS_PID)?,
+                    pc: read_u32(note.desc, regs + REGS_PC)?,
+                    a0: read_u32(note.desc, regs + REGS_AR)?,
+                    a1: read_u32(note.desc, regs + REGS_AR + 4)?,
+                })
+            })
+    }
+
+    fn task_name(&self, tcb: u32) -> Option<String> {
+        TCB_NAME_OFFSETS.iter().find_map(|offset| {
+            let bytes = self.read_bytes(tcb.checked_add(*offset)?, TCB_NAME_LEN)?;
+            let len = bytes.iter().position(|b| *b == 0)?;
+            let name = &bytes[..len];
+            let printable = !name.is_empty() && name.iter().all(|b| b.is_ascii_graphic() || *b == b' ');
+            printable.then(|| String::from_utf8_lossy(name).into_owned())
+        })
+    }
+
+    /// Walks the windowed ABI stack frames of a task. Each frame's base
+    /// save area, just below its stack pointer, holds the caller's return
+    /// address and stack pointer.
+    fn backtrace(&self, task: &TaskRegs) -> Vec<u32> {
+        let mut backtrace = vec![task.pc];
+        let mut next_pc = task.a0;
+        let mut sp = task.a1;
+
+        while next_pc != 0 && backtrace.len() < MAX_BACKTRACE {
+            backtrace.push(process_return_address(next_pc));
+
+            let (Some(pc), Some(next_sp)) = (
+                self.read_mem(sp.wrapping_sub(16)),
+                self.read_mem(sp.wrapping_sub(12)),
+            ) else {
+                break;
+            };
+
+            if next_sp <= sp {
+                break;
+            }
+
+            next_pc = pc;
+            sp = next_sp;
+        }
+
+        backtrace
+    }
+
+    fn report(&self) -> CrashReport {
+        let panic_reason = self.note(b"ESP_PANIC_DETAILS").map(|note| {
+            let text = note.desc.split(|b| *b == 0).next().unwrap_or_default();
+            String::from_utf8_lossy(text).trim().to_owned()
+        });
+
+        let extra_info = self.note(b"EXTRA_INFO");
+        let crashed_tcb = extra_info.and_then(|note| read_u32(note.desc, 0));
+        let exception = extra_info.and_then(|note| {
+            let regs = note.desc.get(4..)?;
+            let reg = |number| regs.chunks_exact(8)
+                .find(|pair| read_u32(pair, 0) == Some(number))
+                .and_then(|pair| read_u32(pair, 4));
+
+            Some(Exception {
+                cause: reg(REG_EXCCAUSE)?,
+                address: reg(REG_EXCVADDR).unwrap_or(0),
+            })
+        });
+
+        // the crashed task is written first, so fall back to that
+        let task = self.tasks()
+            .find(|task| Some(task.tcb) == crashed_tcb)
+            .or_else(|| self.tasks().next());
+
+        CrashReport {
+            panic_reason,
+            exception,
+            backtrace: task.as_ref().map(|task| self.backtrace(task)).unwrap_or_default(),
+            task: task.map(|task| Task {
+                tcb: task.tcb,
+                name: self.task_name(task.tcb),
+            }),
+        }
+    }
+}
+
+fn parse_notes<'a>(mut data: &'a [u8], notes: &mut Vec<Note<'a>>) -> Result<(), DecodeError> {
+    while data.len() >= 12 {
+        let name_size = read_u32(data, 0).unwrap_or(0) as usize;
+        let desc_size = read_u32(data, 4).unwrap_or(0) as usize;
+        let kind = read_u32(data, 8).unwrap_or(0);
+
+        let name_end = 12 + name_size;
+        let desc_start = 12 + align4(name_size);
+        let desc_end = desc_start + desc_size;
+
+        let (Some(name), Some(desc)) = (data.get(12..name_end), data.get(desc_start..desc_end)) else {
+            return Err(DecodeError::Malformed("note out of bounds"));
+        };
+
+        // names are NUL terminated
+        let name = name.strip_suffix(b"\0").unwrap_or(name);
+        notes.push(Note { name, kind, desc });
+
+        data = data.get(desc_start + align4(desc_size)..).unwrap_or_default();
+    }
+
+    Ok(())
+}
+
+/// Return addresses in a0 have the call window size in their top two bits
+/// and point after the call instruction. Fix them up to point at the call.
+fn process_return_address(pc: u32) -> u32 {
+    let pc = if pc & 0x80000000 != 0 {
+        (pc & 0x3fffffff) | 0x40000000
+    } else {
+        pc
+    };
+
+    pc.wrapping_sub(3)
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2)?.try_into().ok().map(u16::from_le_bytes)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4)?.try_into().ok().map(u32::from_le_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STACK: u32 = 0x3ffb0000;
+    const TCB: u32 = STACK;
+
+    fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+        data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn note(name: &str, kind: u32, desc: &[u8]) -> Vec<u8> {
+        let mut name = name.as_bytes().to_vec();
+        name.push(0);
+
+        let mut note = Vec::new();
+        note.extend((name.len() as u32).to_le_bytes());
+        note.extend((desc.len() as u32).to_le_bytes());
+        note.extend(kind.to_le_bytes());
+        note.extend(&name);
+        note.resize(align4(note.len()), 0);
+        note.extend(desc);
+        note.resize(align4(note.len()), 0);
+        note
+    }
+
+    /// A 32 bit little endian ELF with a note segment and one load segment
+    fn elf(notes: &[u8], vaddr: u32, memory: &[u8]) -> Vec<u8> {
+        const EHDR_SIZE: usize = 52;
+        const PHDR_SIZE: usize = 32;
+
+        let notes_offset = EHDR_SIZE + 2 * PHDR_SIZE;
+        let memory_offset = notes_offset + notes.len();
+
+        let mut elf = vec![0; notes_offset];
+        elf[..4].copy_from_slice(ELF_MAGIC);
+        elf[4] = 1;
+        elf[5] = 1;
+        put_u32(&mut elf, 28, EHDR_SIZE as u32);
+        elf[42..44].copy_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+        elf[44..46].copy_from_slice(&2u16.to_le_bytes());
+
+        let segments = [
+            (PT_NOTE, notes_offset, 0, notes.len()),
+            (PT_LOAD, memory_offset, vaddr, memory.len()),
+        ];
+
+        for (index, (kind, offset, vaddr, size)) in segments.into_iter().enumerate() {
+            let ph = EHDR_SIZE + index * PHDR_SIZE;
+            put_u32(&mut elf, ph, kind);
+            put_u32(&mut elf, ph + 4, offset as u32);
+            put_u32(&mut elf, ph + 8, vaddr);
+            put_u32(&mut elf, ph + 16, size as u32);
+            put_u32(&mut elf, ph + 20, size as u32);
+        }
+
+        elf.extend(notes);
+        elf.extend(memory);
+        elf
+    }
+
+    /// Wraps an ELF in a core dump header and checksum. Even variants get
+    /// a CRC32, odd ones a SHA-256.
+    fn dump(elf: &[u8], variant: u32) -> Vec<u8> {
+        let checksum_size = if variant & 1 == 0 { 4 } else { 32 };
+
+        let mut dump = vec![0; 24];
+        let len = dump.len() + elf.len() + checksum_size;
+        put_u32(&mut dump, 0, len as u32);
+        put_u32(&mut dump, 4, 0x0100 | variant);
+        dump.extend(elf);
+
+        if checksum_size == 4 {
+            let crc32 = crc32fast::hash(&dump);
+            dump.extend(crc32.to_le_bytes());
+        } else {
+            let sha256 = Sha256::digest(&dump);
+            dump.extend(sha256);
+        }
+
+        dump
+    }
+
+    /// A crash in a task called "main", two frames deep
+    fn crash_elf() -> Vec<u8> {
+        let mut prstatus = vec![0; PRSTATUS_SIZE + REGS_AR + 8];
+        put_u32(&mut prstatus, PRSTATUS_PID, TCB);
+        put_u32(&mut prstatus, PRSTATUS_SIZE + REGS_PC, 0x400d1000);
+        put_u32(&mut prstatus, PRSTATUS_SIZE + REGS_AR, 0x800d2003);
+        put_u32(&mut prstatus, PRSTATUS_SIZE + REGS_AR + 4, STACK + 0x100);
+
+        let mut extra_info = vec![0; 4 + 16];
+        put_u32(&mut extra_info, 0, TCB);
+        put_u32(&mut extra_info, 4, REG_EXCCAUSE);
+        put_u32(&mut extra_info, 8, 28);
+        put_u32(&mut extra_info, 12, REG_EXCVADDR);
+        put_u32(&mut extra_info, 16, 0xdeadbeef);
+
+        let mut notes = note("CORE", NT_PRSTATUS, &prstatus);
+        notes.extend(note("ESP_PANIC_DETAILS", 0, b"abort() was called\0"));
+        notes.extend(note("EXTRA_INFO", 0, &extra_info));
+
+        let mut memory = vec![0; 0x400];
+        memory[56..61].copy_from_slice(b"main\0");
+        // base save area of the first frame: caller's pc and sp
+        put_u32(&mut memory, 0x100 - 16, 0x800d3003);
+        put_u32(&mut memory, 0x100 - 12, STACK + 0x200);
+
+        elf(&notes, STACK, &memory)
+    }
+
+    fn check_report(report: &CrashReport) {
+        assert_eq!(report.panic_reason.as_deref(), Some("abort() was called"));
+
+        let exception = report.exception.as_ref().unwrap();
+        assert_eq!(exception.cause_name(), "LoadProhibited");
+        assert_eq!(exception.address, 0xdeadbeef);
+
+        let task = report.task.as_ref().unwrap();
+        assert_eq!(task.tcb, TCB);
+        assert_eq!(task.name.as_deref(), Some("main"));
+
+        assert_eq!(report.backtrace, [0x400d1000, 0x400d2000, 0x400d3000]);
+    }
+
+    #[test]
+    fn decodes_crc32_dump() {
+        check_report(&decode(&dump(&crash_elf(), 0)).unwrap());
+    }
+
+    #[test]
+    fn decodes_sha256_dump() {
+        check_report(&decode(&dump(&crash_elf(), 1)).unwrap());
+    }
+
+    #[test]
+    fn rejects_bad_checksums() {
+        let mut crc32 = dump(&crash_elf(), 0);
+        crc32[100] ^= 1;
+        assert!(matches!(decode(&crc32), Err(DecodeError::BadCRC(..))));
+
+        let mut sha256 = dump(&crash_elf(), 1);
+        sha256[100] ^= 1;
+        assert!(matches!(decode(&sha256), Err(DecodeError::BadHash(..))));
+    }
+
+    #[test]
+    fn rejects_other_formats() {
+        let mut legacy = dump(&crash_elf(), 0);
+        put_u32(&mut legacy, 4, 0x0002);
+        assert!(matches!(decode(&legacy), Err(DecodeError::UnsupportedFormat(2))));
+    }
+
+    #[test]
+    fn truncated_dumps_are_errors() {
+        let dump = dump(&crash_elf(), 1);
+
+        for len in 0..dump.len() {
+            assert!(decode(&dump[..len]).is_err(), "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn truncated_elf_does_not_panic() {
+        let elf = crash_elf();
+
+        // checksum is fine, so this is down to the ELF parser
+        for len in 0..elf.len() {
+            let _ = decode(&dump(&elf[..len], 0));
+        }
+
+        assert!(matches!(decode(&dump(&elf[..40], 0)), Err(DecodeError::Malformed(_))));
+    }
+
+    #[test]
+    fn corrupt_elf_is_malformed() {
+        let mut bad_note = crash_elf();
+        // name size of the first note
+        put_u32(&mut bad_note, 116, 0xfffffff0);
+        assert!(matches!(decode(&dump(&bad_note, 0)), Err(DecodeError::Malformed("note out of bounds"))));
+
+        let mut bad_segment = crash_elf();
+        // offset of the load segment
+        put_u32(&mut bad_segment, 52 + 32 + 4, 0xfffffff0);
+        assert!(matches!(decode(&dump(&bad_segment, 0)), Err(DecodeError::Malformed("segment out of bounds"))));
+    }
+
+    #[test]
+    fn endless_stack_stops() {
+        let mut elf = crash_elf();
+        let memory = elf.len() - 0x400;
+        // a frame whose caller's sp points back at itself
+        put_u32(&mut elf, memory + 0x100 - 12, STACK + 0x100);
+
+        let report = decode(&dump(&elf, 0)).unwrap();
+        assert_eq!(report.backtrace, [0x400d1000, 0x400d2000]);
+    }
+}
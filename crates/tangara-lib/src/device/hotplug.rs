@@ -0,0 +1,396 @@
+//! Notifies when Tangaras are plugged in or unplugged.
+//!
+//! On Linux we wait for kernel uevents over netlink, and also watch /dev
+//! with inotify, because netlink uevents don't reach us inside a Flatpak
+//! sandbox. Either source just tells us that *something* changed, so we
+//! then rescan for devices and diff against what we saw last time. Other
+//! platforms poll.
+
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::Stream;
+
+use crate::device::{ConnectionParams, Tangara};
+
+/// How long to wait after a hardware event for things to settle down
+/// before rescanning. udev creates the /dev/serial/by-id symlinks a little
+/// while after the tty device itself appears.
+#[cfg(target_os = "linux")]
+const SETTLE_DURATION: Duration = Duration::from_millis(500);
+
+const POLL_DURATION: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HotplugEvent {
+    Attach(ConnectionParams),
+    Detach(ConnectionParams),
+}
+
+/// Stream of hotplug events. Dropping it stops the threads watching for
+/// them.
+pub struct Watch {
+    events: Pin<Box<async_channel::Receiver<HotplugEvent>>>,
+    _stop: Stop,
+}
+
+impl Stream for Watch {
+    type Item = HotplugEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<HotplugEvent>> {
+        self.events.as_mut().poll_next(cx)
+    }
+}
+
+/// Event sources run until this is dropped. Then the polling thread sees
+/// its channel disconnect, and the Linux sources see the pipe close.
+struct Stop {
+    _poll: mpsc::Sender<()>,
+    #[cfg(target_os = "linux")]
+    _pipe: Option<std::os::fd::OwnedFd>,
+}
+
+/// Watches for devices being attached and detached, including USB serial
+/// bridges as [`Tangara::find_with_bridges`] does. `known` is the set of
+/// devices the caller already knows about, events are reported relative
+/// to that.
+pub fn watch(known: Vec<ConnectionParams>) -> Watch {
+    let (tx, rx) = async_channel::bounded(32);
+    let (tick_tx, tick_rx) = mpsc::channel();
+
+    // once every source has stopped, tick_rx disconnects and the rescan
+    // thread stops too
+    let stop = start_sources(tick_tx);
+
+    std::thread::spawn(move || {
+        let mut current = known;
+
+        loop {
+            // check right away in case something changed before we started
+            // watching, then wait for the next event
+            let devices = Tangara::find_with_bridges();
+
+            for event in diff(&current, &devices) {
+                if tx.send_blocking(event).is_err() {
+                    // receiver dropped, stop watching
+                    return;
+                }
+            }
+
+            current = devices;
+
+            if tick_rx.recv().is_err() {
+                return;
+            }
+
+            settle(&tick_rx);
+        }
+    });
+
+    Watch { events: Box::pin(rx), _stop: stop }
+}
+
+fn diff(old: &[ConnectionParams], new: &[ConnectionParams]) -> Vec<HotplugEvent> {
+    let detached = old.iter()
+        .filter(|params| !new.contains(params))
+        .cloned()
+        .map(HotplugEvent::Detach);
+
+    let attached = new.iter()
+        .filter(|params| !old.contains(params))
+        .cloned()
+        .map(HotplugEvent::Attach);
+
+    detached.chain(attached).collect()
+}
+
+#[cfg(target_os = "linux")]
+fn settle(ticks: &mpsc::Receiver<()>) {
+    // swallow the burst of events that come with each device
+    while ticks.recv_timeout(SETTLE_DURATION).is_ok() {}
+}
+
+#[cfg(not(target_os = "linux"))]
+fn settle(_: &mpsc::Receiver<()>) {}
+
+#[cfg(target_os = "linux")]
+fn start_sources(tick: mpsc::Sender<()>) -> Stop {
+    let (poll_tx, poll_rx) = mpsc::channel();
+
+    let (stop_rx, stop_tx) = match linux::pipe() {
+        Ok(pipe) => pipe,
+        Err(error) => {
+            log::warn!("can't create hotplug stop pipe: {error}");
+            start_polling(tick, poll_rx);
+            return Stop { _poll: poll_tx, _pipe: None };
+        }
+    };
+
+    let mut started = false;
+
+    match linux::Uevents::open(&stop_rx) {
+        Ok(mut uevents) => {
+            let tick = tick.clone();
+            std::thread::spawn(move || uevents.run(tick));
+            started = true;
+        }
+        Err(error) => {
+            log::warn!("can't listen for uevents: {error}");
+        }
+    }
+
+    match linux::DevWatch::open(&stop_rx) {
+        Ok(mut watch) => {
+            let tick = tick.clone();
+            std::thread::spawn(move || watch.run(tick));
+            started = true;
+        }
+        Err(error) => {
+            log::warn!("can't watch /dev with inotify: {error}");
+        }
+    }
+
+    if !started {
+        start_polling(tick, poll_rx);
+    }
+
+    Stop { _poll: poll_tx, _pipe: Some(stop_tx) }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn start_sources(tick: mpsc::Sender<()>) -> Stop {
+    let (poll_tx, poll_rx) = mpsc::channel();
+    start_polling(tick, poll_rx);
+    Stop { _poll: poll_tx }
+}
+
+fn start_polling(tick: mpsc::Sender<()>, stop: mpsc::Receiver<()>) {
+    std::thread::spawn(move || {
+        // nothing is ever sent on stop, it only disconnects
+        while let Err(mpsc::RecvTimeoutError::Timeout) = stop.recv_timeout(POLL_DURATION) {
+            if tick.send(()).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::sync::mpsc;
+
+    const UEVENT_BUFFER_SIZE: usize = 8192;
+    const INOTIFY_BUFFER_SIZE: usize = 4096;
+    const SERIAL_BY_ID: &str = "/dev/serial/by-id";
+
+    /// A pipe whose write end is closed to stop the event sources. Returns
+    /// (read end, write end).
+    pub fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+        let mut fds = [0; 2];
+
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+    }
+
+    /// Waits for `fd` to become readable. Returns false if `stop` became
+    /// readable first, which happens when its write end is closed.
+    fn wait(fd: &OwnedFd, stop: &OwnedFd) -> io::Result<bool> {
+        let mut fds = [
+            libc::pollfd { fd: fd.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+            libc::pollfd { fd: stop.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+        ];
+
+        loop {
+            let rc = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+
+            if rc < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(error);
+            }
+
+            return Ok(fds[1].revents == 0);
+        }
+    }
+
+    /// Kernel uevents, received over netlink.
+    pub struct Uevents {
+        socket: OwnedFd,
+        stop: OwnedFd,
+    }
+
+    impl Uevents {
+        pub fn open(stop: &OwnedFd) -> io::Result<Self> {
+            let stop = stop.try_clone()?;
+
+            let fd = unsafe {
+                libc::socket(
+                    libc::AF_NETLINK,
+                    libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                    libc::NETLINK_KOBJECT_UEVENT,
+                )
+            };
+
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+            let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+            addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+            addr.nl_groups = 1; // kernel uevent multicast group
+
+            let rc = unsafe {
+                libc::bind(
+                    socket.as_raw_fd(),
+                    &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                    std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+                )
+            };
+
+            if rc < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(Uevents { socket, stop })
+        }
+
+        pub fn run(&mut self, tick: mpsc::Sender<()>) {
+            let mut buffer = vec![0u8; UEVENT_BUFFER_SIZE];
+
+            loop {
+                match wait(&self.socket, &self.stop) {
+                    Ok(true) => {}
+                    Ok(false) => return,
+                    Err(error) => {
+                        log::warn!("waiting for uevents: {error}");
+                        return;
+                    }
+                }
+
+                let n = unsafe {
+                    libc::recv(
+                        self.socket.as_raw_fd(),
+                        buffer.as_mut_ptr() as *mut libc::c_void,
+                        buffer.len(),
+                        0,
+                    )
+                };
+
+                if n < 0 {
+                    let error = io::Error::last_os_error();
+                    if error.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    log::warn!("reading uevent: {error}");
+                    return;
+                }
+
+                // uevents are a NUL separated list of KEY=value fields
+                let is_tty = buffer[..n as usize]
+                    .split(|b| *b == 0)
+                    .any(|field| field == b"SUBSYSTEM=tty");
+
+                if is_tty && tick.send(()).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Watches /dev for tty devices coming and going. This works inside
+    /// Flatpak, which gives us the host's /dev but not its uevents.
+    pub struct DevWatch {
+        inotify: OwnedFd,
+        stop: OwnedFd,
+    }
+
+    impl DevWatch {
+        pub fn open(stop: &OwnedFd) -> io::Result<Self> {
+            let stop = stop.try_clone()?;
+            let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let watch = DevWatch {
+                inotify: unsafe { OwnedFd::from_raw_fd(fd) },
+                stop,
+            };
+
+            watch.add_watch("/dev")?;
+            watch.watch_by_id();
+
+            Ok(watch)
+        }
+
+        fn add_watch(&self, path: &str) -> io::Result<libc::c_int> {
+            let path = CString::new(path)?;
+            let mask = libc::IN_CREATE | libc::IN_DELETE;
+            let wd = unsafe { libc::inotify_add_watch(self.inotify.as_raw_fd(), path.as_ptr(), mask) };
+
+            if wd < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(wd)
+            }
+        }
+
+        /// /dev/serial/by-id only exists while a serial device is plugged
+        /// in, so we (re)add the watch for it whenever /dev changes. Adding
+        /// a watch for a path that's already watched is harmless.
+        fn watch_by_id(&self) {
+            let _ = self.add_watch(SERIAL_BY_ID);
+        }
+
+        pub fn run(&mut self, tick: mpsc::Sender<()>) {
+            let mut buffer = vec![0u8; INOTIFY_BUFFER_SIZE];
+
+            loop {
+                match wait(&self.inotify, &self.stop) {
+                    Ok(true) => {}
+                    Ok(false) => return,
+                    Err(error) => {
+                        log::warn!("waiting for inotify events: {error}");
+                        return;
+                    }
+                }
+
+                let n = unsafe {
+                    libc::read(
+                        self.inotify.as_raw_fd(),
+                        buffer.as_mut_ptr() as *mut libc::c_void,
+                        buffer.len(),
+                    )
+                };
+
+                if n < 0 {
+                    let error = io::Error::last_os_error();
+                    if error.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    log::warn!("reading inotify events: {error}");
+                    return;
+                }
+
+                self.watch_by_id();
+
+                if tick.send(()).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
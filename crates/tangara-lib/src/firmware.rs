@@ -1,20 +1,52 @@
+pub mod app_image;
+pub mod builder;
+pub mod partition;
+pub mod signature;
+
 use std::io::{Read, self};
 use std::path::{Path, PathBuf};
 use std::fs::File;
+use std::sync::{Arc, Mutex};
 
 use crc32fast;
+use semver::Version;
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 use zip::ZipArchive;
 use zip::result::ZipError;
 
+use app_image::{AppDescriptor, AppImage, AppImageError, ImageHeader};
+use partition::{LayoutError, LayoutImage, PartitionError, PartitionTable};
+use signature::{TrustedKey, Verification};
+
 const MAX_IMAGE_SIZE: usize = 32 * 1024 * 1024;
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+pub const MANIFEST_FILE: &str = "tangaraflash.json";
 
 pub struct Firmware {
     path: PathBuf,
-    manifest: data::ManifestV0,
+    manifest: Manifest,
+    signature: Option<String>,
+    app: Option<AppDescriptor>,
+    partitions: Option<PartitionTable>,
     images: Vec<Image>,
 }
 
+/// Firmware archive metadata, normalised across manifest versions. Fields
+/// that older manifests don't have are `None`.
+pub struct Manifest {
+    pub manifest_version: usize,
+    pub version: String,
+    pub hardware_revision: Option<String>,
+    pub min_companion_version: Option<Version>,
+    pub release_notes: Option<String>,
+    pub release_date: Option<String>,
+    images: Vec<data::FirmwareImageV1>,
+    /// Exact manifest bytes, which is what signatures are made over
+    json: String,
+}
+
 #[derive(Debug, Error)]
 pub enum OpenError {
     #[error("Unable to open firmware archive: {0}")]
@@ -27,10 +59,24 @@ pub enum OpenError {
     ReadManifest(#[source] std::io::Error),
     #[error("Can't parse firmware manifest: {0}")]
     ParseManifest(#[source] serde_json::Error),
-    #[error("Firmware archive newer than this version of Tangara Flasher supports")]
-    UnsupportedVersion,
+    #[error("Invalid minimum companion version in firmware manifest: {0}")]
+    ParseCompanionVersion(#[source] semver::Error),
+    #[error("Firmware archive version {0} is newer than this version of Tangara Companion supports")]
+    UnsupportedVersion(usize),
+    #[error("Firmware requires Tangara Companion {0} or newer")]
+    CompanionTooOld(Version),
+    #[error("Can't read firmware signature: {0}")]
+    ReadSignature(#[source] std::io::Error),
     #[error("Reading image: {0}: {1}")]
     ReadImage(String, #[source] ReadImageError),
+    #[error("Invalid app image: {0}")]
+    AppImage(#[source] AppImageError),
+    #[error("Manifest says version {manifest}, but app image is version {app}")]
+    VersionMismatch { manifest: String, app: String },
+    #[error("Invalid partition table: {0}")]
+    PartitionTable(#[source] PartitionError),
+    #[error("Bad image layout: {0}")]
+    Layout(#[source] LayoutError),
 }
 
 #[derive(Debug, Error)]
@@ -42,7 +88,11 @@ pub enum ReadImageError {
     #[error("I/O error: {0}")]
     Io(#[from] io::Error),
     #[error("Checksum error: {0:X} (should be {1:X})")]
-    BadCRC(u32, u32)
+    BadCRC(u32, u32),
+    #[error("SHA-256 mismatch: {0} (should be {1})")]
+    BadHash(String, String),
+    #[error("Invalid app image: {0}")]
+    AppImage(#[from] AppImageError),
 }
 
 impl Firmware {
@@ -50,20 +100,126 @@ impl Firmware {
         let file = File::open(path).map_err(OpenError::Open)?;
         let mut zip = ZipArchive::new(file)?;
         let manifest = read_manifest(&mut zip)?;
-        let images = read_images(&mut zip, &manifest.firmware)?;
-        Ok(Firmware {
+
+        if let Some(required) = &manifest.min_companion_version {
+            if *required > companion_version() {
+                return Err(OpenError::CompanionTooOld(required.clone()));
+            }
+        }
+
+        let signature = read_signature(&mut zip)?;
+
+        let archive = Arc::new(Mutex::new(zip));
+        let images = read_images(&archive, &manifest.images)?;
+        let app = read_app_descriptor(&manifest, &images)?;
+        let partitions = read_partition_table(&images)?;
+
+        let firmware = Firmware {
             path: path.to_owned(),
             manifest,
+            signature,
+            app,
+            partitions,
             images,
-        })
+        };
+
+        firmware.validate_layout()?;
+
+        Ok(firmware)
+    }
+
+    /// Version and build information from the app image, if the archive
+    /// contains one
+    pub fn app_descriptor(&self) -> Option<&AppDescriptor> {
+        self.app.as_ref()
+    }
+
+    /// The partition table the archive will flash, if it contains one
+    pub fn partition_table(&self) -> Option<&PartitionTable> {
+        self.partitions.as_ref()
+    }
+
+    /// Flash size the images were built for, from the bootloader or app
+    /// image header.
+    pub fn flash_size(&self) -> Option<u32> {
+        self.images.iter()
+            .filter(|image| image.addr < partition::PARTITION_TABLE_ADDR || image.name == builder::APP_IMAGE)
+            .find_map(|image| image.header.as_ref()?.flash_size)
+    }
+
+    /// ESP-IDF ID of the chip the images were built for, from the
+    /// bootloader or app image header.
+    pub fn chip_id(&self) -> Option<u16> {
+        self.images.iter()
+            .filter(|image| image.addr < partition::PARTITION_TABLE_ADDR || image.name == builder::APP_IMAGE)
+            .find_map(|image| Some(image.header.as_ref()?.chip_id))
+    }
+
+    /// End of the last image, so the least flash the device needs.
+    pub fn flash_end(&self) -> u32 {
+        // opening the archive checks images don't run off the end of the
+        // address space, saturating is just belt and braces
+        self.images.iter()
+            .map(|image| image.addr.saturating_add(image.size))
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn validate_layout(&self) -> Result<(), OpenError> {
+        let images = self.images.iter()
+            .map(|image| LayoutImage {
+                name: &image.name,
+                addr: image.addr,
+                len: image.size,
+                is_app: image.is_app(),
+            })
+            .collect::<Vec<_>>();
+
+        partition::validate(self.partitions.as_ref(), &images, self.flash_size())
+            .map_err(OpenError::Layout)
+    }
+
+    /// Checks the archive's signature against the trusted keys compiled
+    /// into this library.
+    pub fn verify(&self) -> Verification {
+        self.verify_with(signature::TRUSTED_KEYS)
+    }
+
+    pub fn verify_with(&self, trusted: &[TrustedKey]) -> Verification {
+        signature::verify(
+            &self.manifest.json,
+            self.manifest.manifest_version,
+            self.signature.as_deref(),
+            trusted,
+        )
     }
 
     pub fn path(&self) -> &Path {
         &self.path
     }
 
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
     pub fn version(&self) -> &str {
-        &self.manifest.firmware.version
+        &self.manifest.version
+    }
+
+    pub fn hardware_revision(&self) -> Option<&str> {
+        self.manifest.hardware_revision.as_deref()
+    }
+
+    pub fn min_companion_version(&self) -> Option<&Version> {
+        self.manifest.min_companion_version.as_ref()
+    }
+
+    pub fn release_notes(&self) -> Option<&str> {
+        self.manifest.release_notes.as_deref()
+    }
+
+    pub fn release_date(&self) -> Option<&str> {
+        self.manifest.release_date.as_deref()
     }
 
     pub fn images(&self) -> &[Image] {
@@ -71,14 +227,150 @@ impl Firmware {
     }
 }
 
+/// An image in a firmware archive. Image data isn't held in memory, it's
+/// read out of the archive when needed, and checked against the archive's
+/// checksums every time it is.
 pub struct Image {
     pub name: String,
     pub addr: u32,
-    pub data: Vec<u8>,
+    /// Uncompressed size in bytes
+    pub size: u32,
+    /// Only present in v1 and later manifests
+    pub sha256: Option<[u8; 32]>,
+    /// Optional images may be left out of an archive, and skipped by the
+    /// flasher when asked
+    pub optional: bool,
+    /// ESP-IDF image header, if the image starts with one
+    header: Option<ImageHeader>,
+    archive: Archive,
+}
+
+type Archive = Arc<Mutex<ZipArchive<File>>>;
+
+impl Image {
+    pub fn sha256_hex(&self) -> Option<String> {
+        self.sha256.as_ref().map(|hash| hex(hash))
+    }
+
+    pub fn header(&self) -> Option<&ImageHeader> {
+        self.header.as_ref()
+    }
+
+    /// Whether this is an app image, rather than the bootloader or data
+    /// for a data partition.
+    pub fn is_app(&self) -> bool {
+        self.addr >= partition::PARTITION_TABLE_ADDR && self.header.is_some()
+    }
+
+    /// Reads the whole image into memory, checking it as it's read.
+    pub fn read(&self) -> Result<Vec<u8>, ReadImageError> {
+        let mut data = Vec::with_capacity(self.size as usize);
+        self.stream(|chunk| data.extend_from_slice(chunk))?;
+        Ok(data)
+    }
+
+    /// Checks the image against its checksums without keeping it in
+    /// memory. App images are read in full, to also check the hash
+    /// appended by the build.
+    pub fn verify(&self) -> Result<(), ReadImageError> {
+        if self.is_app() {
+            AppImage::parse(&self.read()?)?;
+        } else {
+            self.stream(|_| {})?;
+        }
+
+        Ok(())
+    }
+
+    /// SHA-256 of the image. This is the hash from the manifest when it
+    /// has one, otherwise it's computed by reading the image through.
+    pub fn checksum(&self) -> Result<[u8; 32], ReadImageError> {
+        match self.sha256 {
+            Some(hash) => Ok(hash),
+            None => self.stream(|_| {}),
+        }
+    }
+
+    /// Reads up to `len` bytes from the start of the image. This doesn't
+    /// check the image's checksums.
+    fn read_prefix(&self, len: usize) -> Result<Vec<u8>, ReadImageError> {
+        let mut zip = self.archive.lock().unwrap();
+        let file = zip.by_name(&self.name)?;
+
+        let mut data = Vec::with_capacity(len);
+        file.take(len as u64).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Reads the image through in chunks, checking it against its
+    /// checksums. Returns its SHA-256.
+    fn stream(&self, mut f: impl FnMut(&[u8])) -> Result<[u8; 32], ReadImageError> {
+        let mut zip = self.archive.lock().unwrap();
+        let mut file = zip.by_name(&self.name)?;
+
+        let mut crc32 = crc32fast::Hasher::new();
+        let mut sha256 = Sha256::new();
+        let mut buffer = vec![0; READ_CHUNK_SIZE];
+
+        loop {
+            let n = file.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+
+            let chunk = &buffer[..n];
+            crc32.update(chunk);
+            sha256.update(chunk);
+            f(chunk);
+        }
+
+        let actual_crc32 = crc32.finalize();
+        let expected_crc32 = file.crc32();
+
+        if actual_crc32 != expected_crc32 {
+            return Err(ReadImageError::BadCRC(actual_crc32, expected_crc32));
+        }
+
+        let hash: [u8; 32] = sha256.finalize().into();
+
+        if let Some(expected) = &self.sha256 {
+            if hash != *expected {
+                return Err(ReadImageError::BadHash(hex(&hash), hex(expected)));
+            }
+        }
+
+        Ok(hash)
+    }
+}
+
+/// The version of Tangara Companion, for checking against the minimum
+/// version a firmware archive requires. All crates in the workspace share
+/// a version.
+fn companion_version() -> Version {
+    Version::parse(env!("CARGO_PKG_VERSION")).expect("valid package version")
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+pub fn parse_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != N * 2 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut bytes = [0u8; N];
+    for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).ok()?;
+        *byte = u8::from_str_radix(digits, 16).ok()?;
+    }
+
+    Some(bytes)
 }
 
 pub mod data {
-    use serde::Deserialize;
+    use serde::{Deserialize, Deserializer};
+    use serde::de::Error;
 
     #[derive(Deserialize)]
     pub struct Manifest {
@@ -102,10 +394,60 @@ pub mod data {
         pub addr: u32,
         pub name: String,
     }
+
+    #[derive(Deserialize)]
+    pub struct ManifestV1 {
+        pub firmware: FirmwareV1,
+    }
+
+    #[derive(Deserialize)]
+    pub struct FirmwareV1 {
+        pub version: String,
+        /// Hardware revision this firmware is built for, eg. "7"
+        #[serde(default)]
+        pub hardware: Option<String>,
+        /// Oldest Tangara Companion version that can flash this firmware
+        #[serde(default)]
+        pub min_companion_version: Option<String>,
+        #[serde(default)]
+        pub release_notes: Option<String>,
+        /// ISO 8601 date, eg. "2025-02-14"
+        #[serde(default)]
+        pub release_date: Option<String>,
+        pub images: Vec<FirmwareImageV1>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct FirmwareImageV1 {
+        pub addr: u32,
+        pub name: String,
+        #[serde(deserialize_with = "deserialize_sha256")]
+        pub sha256: Option<[u8; 32]>,
+        #[serde(default)]
+        pub optional: bool,
+    }
+
+    impl From<FirmwareImage> for FirmwareImageV1 {
+        fn from(image: FirmwareImage) -> Self {
+            FirmwareImageV1 {
+                addr: image.addr,
+                name: image.name,
+                sha256: None,
+                optional: false,
+            }
+        }
+    }
+
+    fn deserialize_sha256<'de, D: Deserializer<'de>>(de: D) -> Result<Option<[u8; 32]>, D::Error> {
+        let hex = String::deserialize(de)?;
+        super::parse_hex(&hex)
+            .map(Some)
+            .ok_or_else(|| D::Error::custom(format!("invalid sha256: {hex}")))
+    }
 }
 
-fn read_manifest(zip: &mut ZipArchive<File>) -> Result<data::ManifestV0, OpenError> {
-    let mut manifest_file = zip.by_name("tangaraflash.json").map_err(|error| {
+fn read_manifest(zip: &mut ZipArchive<File>) -> Result<Manifest, OpenError> {
+    let mut manifest_file = zip.by_name(MANIFEST_FILE).map_err(|error| {
         match error {
             ZipError::FileNotFound => OpenError::NoManifest,
             _ => OpenError::Zip(error),
@@ -119,56 +461,189 @@ fn read_manifest(zip: &mut ZipArchive<File>) -> Result<data::ManifestV0, OpenErr
     let manifest = serde_json::from_str::<data::Manifest>(&manifest_json)
         .map_err(OpenError::ParseManifest)?;
 
-    if manifest.version > 0 {
-        return Err(OpenError::UnsupportedVersion);
+    match manifest.version {
+        0 => {
+            let manifest = serde_json::from_value::<data::ManifestV0>(manifest.data)
+                .map_err(OpenError::ParseManifest)?;
+
+            Ok(Manifest {
+                manifest_version: 0,
+                version: manifest.firmware.version,
+                hardware_revision: None,
+                min_companion_version: None,
+                release_notes: None,
+                release_date: None,
+                images: manifest.firmware.images.into_iter().map(Into::into).collect(),
+                json: manifest_json,
+            })
+        }
+        1 => {
+            let manifest = serde_json::from_value::<data::ManifestV1>(manifest.data)
+                .map_err(OpenError::ParseManifest)?;
+
+            let firmware = manifest.firmware;
+
+            let min_companion_version = firmware.min_companion_version
+                .map(|version| Version::parse(&version))
+                .transpose()
+                .map_err(OpenError::ParseCompanionVersion)?;
+
+            Ok(Manifest {
+                manifest_version: 1,
+                version: firmware.version,
+                hardware_revision: firmware.hardware,
+                min_companion_version,
+                release_notes: firmware.release_notes,
+                release_date: firmware.release_date,
+                images: firmware.images,
+                json: manifest_json,
+            })
+        }
+        version => Err(OpenError::UnsupportedVersion(version)),
     }
+}
 
-    let manifest = serde_json::from_value::<data::ManifestV0>(manifest.data)
-        .map_err(OpenError::ParseManifest)?;
+fn read_app_descriptor(manifest: &Manifest, images: &[Image]) -> Result<Option<AppDescriptor>, OpenError> {
+    let Some(image) = images.iter().find(|image| image.name == builder::APP_IMAGE) else {
+        return Ok(None);
+    };
+
+    let prefix = image.read_prefix(app_image::APP_DESC_END)
+        .map_err(|error| OpenError::ReadImage(image.name.clone(), error))?;
+
+    let app = AppDescriptor::parse(&prefix).map_err(OpenError::AppImage)?;
+
+    // release tags are sometimes prefixed with 'v', the version isn't
+    let app_version = app.version.trim_start_matches('v');
+    let manifest_version = manifest.version.trim_start_matches('v');
+
+    if app_version != manifest_version {
+        return Err(OpenError::VersionMismatch {
+            manifest: manifest.version.clone(),
+            app: app.version.clone(),
+        });
+    }
+
+    Ok(Some(app))
+}
+
+fn read_partition_table(images: &[Image]) -> Result<Option<PartitionTable>, OpenError> {
+    let Some(image) = images.iter().find(|image| image.addr == partition::PARTITION_TABLE_ADDR) else {
+        return Ok(None);
+    };
+
+    let data = image.read()
+        .map_err(|error| OpenError::ReadImage(image.name.clone(), error))?;
+
+    PartitionTable::parse(&data)
+        .map(Some)
+        .map_err(OpenError::PartitionTable)
+}
+
+fn read_signature(zip: &mut ZipArchive<File>) -> Result<Option<String>, OpenError> {
+    let mut signature_file = match zip.by_name(signature::SIGNATURE_FILE) {
+        Ok(file) => file,
+        Err(ZipError::FileNotFound) => { return Ok(None); }
+        Err(error) => { return Err(OpenError::Zip(error)); }
+    };
+
+    let mut signature = String::new();
+    signature_file.read_to_string(&mut signature)
+        .map_err(OpenError::ReadSignature)?;
 
-    Ok(manifest)
+    Ok(Some(signature))
 }
 
-fn read_images(zip: &mut ZipArchive<File>, firmware: &data::Firmware)
+fn read_images(archive: &Archive, entries: &[data::FirmwareImageV1])
     -> Result<Vec<Image>, OpenError>
 {
     let mut images = Vec::new();
 
-    for image in &firmware.images {
-        let data = read_image_data(zip, &image.name).map_err(|error| {
-            OpenError::ReadImage(image.name.clone(), error)
-        })?;
+    for image in entries {
+        let size = match image_size(archive, &image.name) {
+            Ok(size) => size,
+            Err(ReadImageError::NotFound(ZipError::FileNotFound)) if image.optional => {
+                log::info!("optional image {} not in archive, skipping", image.name);
+                continue;
+            }
+            Err(error) => {
+                return Err(OpenError::ReadImage(image.name.clone(), error));
+            }
+        };
 
-        log::debug!("image {} @ {:x?}, {} bytes", image.name, image.addr, data.len());
+        log::debug!("image {} @ {:x?}, {} bytes", image.name, image.addr, size);
 
-        images.push(Image {
+        let mut image = Image {
             name: image.name.clone(),
             addr: image.addr,
-            data: data,
-        });
+            size,
+            sha256: image.sha256,
+            optional: image.optional,
+            header: None,
+            archive: archive.clone(),
+        };
+
+        let prefix = image.read_prefix(app_image::IMAGE_HEADER_SIZE)
+            .map_err(|error| OpenError::ReadImage(image.name.clone(), error))?;
+
+        image.header = ImageHeader::parse(&prefix).ok();
+
+        images.push(image);
     }
 
     Ok(images)
 }
 
-fn read_image_data(zip: &mut ZipArchive<File>, name: &str)
-    -> Result<Vec<u8>, ReadImageError>
-{
-    let mut file = zip.by_name(name)?;
+fn image_size(archive: &Archive, name: &str) -> Result<u32, ReadImageError> {
+    let mut zip = archive.lock().unwrap();
+    let file = zip.by_name(name)?;
 
-    let size = usize::try_from(file.size()).ok()
-        .filter(|sz| *sz < MAX_IMAGE_SIZE)
-        .ok_or_else(|| ReadImageError::TooLarge(file.size()))?;
+    usize::try_from(file.size()).ok()
+        .filter(|sz| *sz <= MAX_IMAGE_SIZE)
+        .map(|sz| sz as u32)
+        .ok_or_else(|| ReadImageError::TooLarge(file.size()))
+}
 
-    let mut data = vec![0; size];
-    file.read_exact(&mut data)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::backup::FULL_IMAGE;
 
-    let crc32 = file.crc32();
-    let expected_crc32 = crc32fast::hash(&data);
+    fn write_backup(name: &str, size: usize) -> PathBuf {
+        let path = std::env::temp_dir()
+            .join(format!("tangara-test-{}-{name}.tra", std::process::id()));
 
-    if crc32 != expected_crc32 {
-        Err(ReadImageError::BadCRC(crc32, expected_crc32))
-    } else {
-        Ok(data)
+        // laid out the way a full backup is
+        builder::Builder::new()
+            .version("backup-1")
+            .release_notes("Backup of the whole flash chip read from a Tangara.")
+            .image(FULL_IMAGE, 0, vec![0xff; size])
+            .write(&path)
+            .unwrap();
+
+        path
+    }
+
+    #[test]
+    fn largest_backup_round_trips() {
+        let path = write_backup("largest", MAX_IMAGE_SIZE);
+        let result = Firmware::open(&path);
+        let data = result.as_ref().ok().map(|firmware| firmware.images()[0].read());
+        let _ = std::fs::remove_file(&path);
+
+        let firmware = result.unwrap();
+        assert_eq!(firmware.version(), "backup-1");
+        assert_eq!(firmware.images().len(), 1);
+        assert_eq!(firmware.images()[0].size as usize, MAX_IMAGE_SIZE);
+        assert_eq!(data.unwrap().unwrap().len(), MAX_IMAGE_SIZE);
+    }
+
+    #[test]
+    fn oversized_image_is_refused() {
+        let path = write_backup("oversized", MAX_IMAGE_SIZE + 1);
+        let result = Firmware::open(&path);
+        let _ = std::fs::remove_file(&path);
+
+        assert!(matches!(result, Err(OpenError::ReadImage(_, ReadImageError::TooLarge(_)))));
     }
 }
@@ -0,0 +1,180 @@
+//! Parser for ESP-IDF app images, as produced by esptool's elf2image.
+//!
+//! An app image starts with a 24 byte image header, followed by segments
+//! each with their own 8 byte header. The first segment begins with the
+//! app descriptor, which holds the version and build information.
+
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+const IMAGE_MAGIC: u8 = 0xe9;
+const APP_DESC_MAGIC: u32 = 0xabcd5432;
+const SEGMENT_HEADER_SIZE: usize = 8;
+const APP_DESC_SIZE: usize = 176;
+
+pub const IMAGE_HEADER_SIZE: usize = 24;
+/// How much of the start of an app image is needed to parse its app
+/// descriptor
+pub const APP_DESC_END: usize = IMAGE_HEADER_SIZE + SEGMENT_HEADER_SIZE + APP_DESC_SIZE;
+const CHECKSUM_ALIGN: usize = 16;
+
+/// Chip ids from esp_chip_id_t
+pub const CHIP_ID_ESP32: u16 = 0x0000;
+
+#[derive(Debug, Error)]
+pub enum AppImageError {
+    #[error("image truncated")]
+    Truncated,
+    #[error("bad image magic {0:#04x}")]
+    BadMagic(u8),
+    #[error("bad app descriptor magic {0:#010x}")]
+    BadAppDescMagic(u32),
+    #[error("appended SHA-256 does not match image contents")]
+    BadHash,
+}
+
+#[derive(Clone, Debug)]
+pub struct ImageHeader {
+    pub segment_count: u8,
+    pub flash_mode: u8,
+    pub flash_freq: u8,
+    /// Flash size in bytes, from the size nibble of the header
+    pub flash_size: Option<u32>,
+    pub entry_addr: u32,
+    pub chip_id: u16,
+    pub min_chip_rev: u8,
+    pub hash_appended: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Segment {
+    pub load_addr: u32,
+    pub len: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct AppDescriptor {
+    pub secure_version: u32,
+    pub version: String,
+    pub project_name: String,
+    pub time: String,
+    pub date: String,
+    pub idf_version: String,
+    pub elf_sha256: [u8; 32],
+}
+
+#[derive(Clone, Debug)]
+pub struct AppImage {
+    pub header: ImageHeader,
+    pub segments: Vec<Segment>,
+    pub descriptor: AppDescriptor,
+    /// SHA-256 of the image, appended after it by the build, if present.
+    /// This has already been checked against the image contents.
+    pub sha256: Option<[u8; 32]>,
+}
+
+impl AppImage {
+    pub fn parse(data: &[u8]) -> Result<Self, AppImageError> {
+        let header = ImageHeader::parse(data)?;
+
+        let mut segments = Vec::new();
+        let mut offset = IMAGE_HEADER_SIZE;
+
+        for _ in 0..header.segment_count {
+            let load_addr = read_u32(data, offset)?;
+            let len = read_u32(data, offset + 4)?;
+            segments.push(Segment { load_addr, len });
+            offset += SEGMENT_HEADER_SIZE + len as usize;
+        }
+
+        if offset > data.len() {
+            return Err(AppImageError::Truncated);
+        }
+
+        let descriptor = AppDescriptor::parse(data)?;
+
+        // segments are followed by padding, then a checksum byte in the
+        // last byte of a 16 byte block, then the optional SHA-256
+        let checksum_end = (offset / CHECKSUM_ALIGN + 1) * CHECKSUM_ALIGN;
+
+        let sha256 = if header.hash_appended {
+            let appended: [u8; 32] = data.get(checksum_end..checksum_end + 32)
+                .ok_or(AppImageError::Truncated)?
+                .try_into()
+                .expect("32 byte slice");
+
+            let hash: [u8; 32] = Sha256::digest(&data[..checksum_end]).into();
+            if hash != appended {
+                return Err(AppImageError::BadHash);
+            }
+
+            Some(hash)
+        } else {
+            None
+        };
+
+        Ok(AppImage { header, segments, descriptor, sha256 })
+    }
+}
+
+impl ImageHeader {
+    pub fn parse(data: &[u8]) -> Result<Self, AppImageError> {
+        let header = data.get(..IMAGE_HEADER_SIZE).ok_or(AppImageError::Truncated)?;
+
+        if header[0] != IMAGE_MAGIC {
+            return Err(AppImageError::BadMagic(header[0]));
+        }
+
+        Ok(ImageHeader {
+            segment_count: header[1],
+            flash_mode: header[2],
+            flash_freq: header[3] & 0x0f,
+            flash_size: flash_size(header[3] >> 4),
+            entry_addr: read_u32(header, 4)?,
+            chip_id: u16::from_le_bytes([header[12], header[13]]),
+            min_chip_rev: header[14],
+            hash_appended: header[23] == 1,
+        })
+    }
+}
+
+impl AppDescriptor {
+    pub fn parse(data: &[u8]) -> Result<Self, AppImageError> {
+        let offset = IMAGE_HEADER_SIZE + SEGMENT_HEADER_SIZE;
+        let desc = data.get(offset..APP_DESC_END).ok_or(AppImageError::Truncated)?;
+
+        let magic = read_u32(desc, 0)?;
+        if magic != APP_DESC_MAGIC {
+            return Err(AppImageError::BadAppDescMagic(magic));
+        }
+
+        Ok(AppDescriptor {
+            secure_version: read_u32(desc, 4)?,
+            version: read_str(&desc[16..48]),
+            project_name: read_str(&desc[48..80]),
+            time: read_str(&desc[80..96]),
+            date: read_str(&desc[96..112]),
+            idf_version: read_str(&desc[112..144]),
+            elf_sha256: desc[144..176].try_into().expect("32 byte slice"),
+        })
+    }
+}
+
+fn flash_size(nibble: u8) -> Option<u32> {
+    match nibble {
+        0..=7 => Some((1024 * 1024) << nibble),
+        _ => None,
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, AppImageError> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().expect("4 byte slice")))
+        .ok_or(AppImageError::Truncated)
+}
+
+/// Reads a NUL padded string field
+fn read_str(field: &[u8]) -> String {
+    let len = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..len]).trim().to_owned()
+}
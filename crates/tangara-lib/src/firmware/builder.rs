@@ -0,0 +1,272 @@
+//! Builds TRA (Tangara Release Archive) files.
+//!
+//! Archives are written reproducibly: entries are always in the same order
+//! with fixed timestamps and permissions, so the same images always produce
+//! a byte-identical archive.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use ed25519_dalek::SigningKey;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, DateTime, ZipWriter};
+
+use super::{hex, signature, MANIFEST_FILE};
+use super::app_image::{AppImage, AppImageError};
+
+/// The images in a Tangara firmware release, with their flash addresses
+/// and where to find them in a built tangara-fw repository.
+pub const TANGARA_LAYOUT: &[LayoutImage] = &[
+    LayoutImage { name: "bootloader.bin", addr: 0x1000, path: "build/bootloader/bootloader.bin" },
+    LayoutImage { name: "partition-table.bin", addr: 0x8000, path: "build/partition_table/partition-table.bin" },
+    LayoutImage { name: "ota_data_initial.bin", addr: 0xd000, path: "build/ota_data_initial.bin" },
+    LayoutImage { name: "tangara.bin", addr: 0x10000, path: "build/tangara.bin" },
+    LayoutImage { name: "Generic.LC_COLLATE", addr: 0x810000, path: "tools/collate/Generic.LC_COLLATE" },
+    LayoutImage { name: "lua.bin", addr: 0xb10000, path: "build/lua.bin" },
+    LayoutImage { name: "repl.bin", addr: 0xf10000, path: "build/repl.bin" },
+];
+
+/// Name of the application image, which the version is read from.
+pub const APP_IMAGE: &str = "tangara.bin";
+
+pub struct LayoutImage {
+    pub name: &'static str,
+    pub addr: u32,
+    pub path: &'static str,
+}
+
+#[derive(Debug, Error)]
+pub enum BuildError {
+    #[error("Reading image {0}: {1}")]
+    ReadImage(PathBuf, #[source] io::Error),
+    #[error("No version given, and no {APP_IMAGE} to read it from")]
+    NoAppImage,
+    #[error("No version found in {APP_IMAGE}")]
+    NoVersion,
+    #[error("Version {given} doesn't match {app} in {APP_IMAGE}")]
+    VersionMismatch { given: String, app: String },
+    #[error("Reading {APP_IMAGE}: {0}")]
+    AppImage(#[source] AppImageError),
+    #[error("No images to pack")]
+    NoImages,
+    #[error("Duplicate image name: {0}")]
+    DuplicateImage(String),
+    #[error("Writing archive: {0}")]
+    Write(#[from] io::Error),
+    #[error("Writing archive: {0}")]
+    Zip(#[from] zip::result::ZipError),
+}
+
+struct BuilderImage {
+    name: String,
+    addr: u32,
+    data: Vec<u8>,
+    optional: bool,
+}
+
+#[derive(Default)]
+pub struct Builder {
+    version: Option<String>,
+    hardware_revision: Option<String>,
+    min_companion_version: Option<String>,
+    release_notes: Option<String>,
+    release_date: Option<String>,
+    signing_key: Option<SigningKey>,
+    images: Vec<BuilderImage>,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a builder with the standard set of images from a built
+    /// tangara-fw repository.
+    pub fn from_firmware_repo(repo: &Path) -> Result<Self, BuildError> {
+        let mut builder = Self::new();
+
+        for image in TANGARA_LAYOUT {
+            builder = builder.image_file(image.name, image.addr, &repo.join(image.path))?;
+        }
+
+        Ok(builder)
+    }
+
+    pub fn version(mut self, version: impl Into<String>) -> Self {
+        self.version = Some(version.into());
+        self
+    }
+
+    pub fn hardware_revision(mut self, revision: impl Into<String>) -> Self {
+        self.hardware_revision = Some(revision.into());
+        self
+    }
+
+    pub fn min_companion_version(mut self, version: impl Into<String>) -> Self {
+        self.min_companion_version = Some(version.into());
+        self
+    }
+
+    pub fn release_notes(mut self, notes: impl Into<String>) -> Self {
+        self.release_notes = Some(notes.into());
+        self
+    }
+
+    pub fn release_date(mut self, date: impl Into<String>) -> Self {
+        self.release_date = Some(date.into());
+        self
+    }
+
+    /// Signs the manifest, and so every image, with `key`.
+    pub fn sign_with(mut self, key: SigningKey) -> Self {
+        self.signing_key = Some(key);
+        self
+    }
+
+    pub fn image(mut self, name: impl Into<String>, addr: u32, data: Vec<u8>) -> Self {
+        self.images.push(BuilderImage { name: name.into(), addr, data, optional: false });
+        self
+    }
+
+    pub fn optional_image(mut self, name: impl Into<String>, addr: u32, data: Vec<u8>) -> Self {
+        self.images.push(BuilderImage { name: name.into(), addr, data, optional: true });
+        self
+    }
+
+    /// Drops the image with this name, if there is one, so that it can be
+    /// replaced.
+    pub fn without_image(mut self, name: &str) -> Self {
+        self.images.retain(|image| image.name != name);
+        self
+    }
+
+    pub fn image_file(self, name: impl Into<String>, addr: u32, path: &Path) -> Result<Self, BuildError> {
+        let data = std::fs::read(path)
+            .map_err(|error| BuildError::ReadImage(path.to_owned(), error))?;
+        Ok(self.image(name, addr, data))
+    }
+
+    /// The firmware version, either as given or read from the app image.
+    /// A given version has to agree with the app image, if there is one,
+    /// or the archive won't open.
+    pub fn resolve_version(&self) -> Result<String, BuildError> {
+        let app = self.images.iter().find(|image| image.name == APP_IMAGE);
+
+        let (version, app) = match (&self.version, app) {
+            (Some(version), None) => return Ok(version.clone()),
+            (None, None) => return Err(BuildError::NoAppImage),
+            (version, Some(app)) => (version, app),
+        };
+
+        let image = AppImage::parse(&app.data).map_err(BuildError::AppImage)?;
+
+        if image.descriptor.version.is_empty() {
+            return Err(BuildError::NoVersion);
+        }
+
+        match version {
+            // release tags are sometimes prefixed with 'v', the app version isn't
+            Some(version) if version.trim_start_matches('v') != image.descriptor.version.trim_start_matches('v') => {
+                Err(BuildError::VersionMismatch {
+                    given: version.clone(),
+                    app: image.descriptor.version,
+                })
+            }
+            Some(version) => Ok(version.clone()),
+            None => Ok(image.descriptor.version),
+        }
+    }
+
+    /// Conventional file name for an archive of this version.
+    pub fn file_name(&self) -> Result<String, BuildError> {
+        Ok(format!("tangarafw-v{}.tra", self.resolve_version()?))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), BuildError> {
+        let manifest = self.manifest()?;
+
+        let mut file = File::create(path)?;
+        self.write_to(&mut file, &manifest)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    fn write_to<W: Write + io::Seek>(&self, out: W, manifest: &str) -> Result<(), BuildError> {
+        let options = FileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .compression_level(Some(9))
+            .last_modified_time(DateTime::default())
+            .unix_permissions(0o644);
+
+        let mut zip = ZipWriter::new(out);
+
+        zip.start_file(MANIFEST_FILE, options)?;
+        zip.write_all(manifest.as_bytes())?;
+
+        if let Some(key) = &self.signing_key {
+            zip.start_file(signature::SIGNATURE_FILE, options)?;
+            zip.write_all(signature::sign(manifest, key).as_bytes())?;
+        }
+
+        for image in &self.images {
+            zip.start_file(image.name.as_str(), options)?;
+            zip.write_all(&image.data)?;
+        }
+
+        zip.finish()?;
+        Ok(())
+    }
+
+    fn manifest(&self) -> Result<String, BuildError> {
+        if self.images.is_empty() {
+            return Err(BuildError::NoImages);
+        }
+
+        for (index, image) in self.images.iter().enumerate() {
+            if self.images[..index].iter().any(|other| other.name == image.name) {
+                return Err(BuildError::DuplicateImage(image.name.clone()));
+            }
+        }
+
+        let images = self.images.iter().map(|image| {
+            let sha256 = Sha256::digest(&image.data);
+            json!({
+                "addr": image.addr,
+                "name": image.name,
+                "sha256": hex(&sha256),
+                "optional": image.optional,
+            })
+        }).collect::<Vec<_>>();
+
+        let mut firmware = json!({
+            "version": self.resolve_version()?,
+            "images": images,
+        });
+
+        let optional = [
+            ("hardware", &self.hardware_revision),
+            ("min_companion_version", &self.min_companion_version),
+            ("release_notes", &self.release_notes),
+            ("release_date", &self.release_date),
+        ];
+
+        for (key, value) in optional {
+            if let Some(value) = value {
+                firmware[key] = json!(value);
+            }
+        }
+
+        let manifest = json!({
+            "version": 1,
+            "data": {
+                "firmware": firmware,
+            },
+        });
+
+        Ok(serde_json::to_string_pretty(&manifest).expect("serialize manifest"))
+    }
+}
@@ -0,0 +1,259 @@
+//! ESP-IDF partition tables, and checking that firmware images land where
+//! they're supposed to.
+
+use md5::{Digest, Md5};
+use thiserror::Error;
+
+pub const PARTITION_TABLE_ADDR: u32 = 0x8000;
+pub const PARTITION_TABLE_SIZE: u32 = 0xc00;
+
+const ENTRY_SIZE: usize = 32;
+const ENTRY_MAGIC: [u8; 2] = [0xaa, 0x50];
+const MD5_MAGIC: [u8; 2] = [0xeb, 0xeb];
+
+#[derive(Debug, Error)]
+pub enum PartitionError {
+    #[error("partition table truncated")]
+    Truncated,
+    #[error("partition table MD5 mismatch")]
+    BadMd5,
+    #[error("partition table is empty")]
+    Empty,
+}
+
+#[derive(Debug, Error)]
+pub enum LayoutError {
+    #[error("{image} at {addr:#x} extends past the end of {flash_size} byte flash")]
+    PastEndOfFlash { image: String, addr: u32, flash_size: u32 },
+    #[error("{image} at {addr:#x} extends past the end of the address space")]
+    PastEndOfAddressSpace { image: String, addr: u32 },
+    #[error("{image} at {addr:#x} is not inside any partition")]
+    NoPartition { image: String, addr: u32 },
+    #[error("{image} at {addr:#x} overruns partition {partition}")]
+    OverrunsPartition { image: String, addr: u32, partition: String },
+    #[error("{image} is an app image, but {partition} is not an app partition")]
+    AppInDataPartition { image: String, partition: String },
+    #[error("{image} would overwrite app partition {partition}")]
+    DataInAppPartition { image: String, partition: String },
+    #[error("{image} overlaps {other}")]
+    Overlap { image: String, other: String },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartitionType {
+    App,
+    Data,
+    Other(u8),
+}
+
+#[derive(Clone, Debug)]
+pub struct Partition {
+    pub label: String,
+    pub kind: PartitionType,
+    pub subtype: u8,
+    pub offset: u32,
+    pub size: u32,
+    pub flags: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct PartitionTable {
+    pub partitions: Vec<Partition>,
+}
+
+/// What's known about an image for checking where it goes.
+pub struct LayoutImage<'a> {
+    pub name: &'a str,
+    pub addr: u32,
+    pub len: u32,
+    pub is_app: bool,
+}
+
+impl Partition {
+    pub fn end(&self) -> u32 {
+        self.offset.saturating_add(self.size)
+    }
+
+    pub fn contains(&self, addr: u32, len: u32) -> bool {
+        addr >= self.offset && addr.saturating_add(len) <= self.end()
+    }
+
+    pub fn type_name(&self) -> String {
+        match self.kind {
+            PartitionType::App => "app".to_owned(),
+            PartitionType::Data => "data".to_owned(),
+            PartitionType::Other(kind) => format!("{kind:#04x}"),
+        }
+    }
+
+    pub fn subtype_name(&self) -> String {
+        let name = match (self.kind, self.subtype) {
+            (PartitionType::App, 0x00) => "factory",
+            (PartitionType::App, 0x20) => "test",
+            (PartitionType::Data, 0x00) => "ota",
+            (PartitionType::Data, 0x01) => "phy",
+            (PartitionType::Data, 0x02) => "nvs",
+            (PartitionType::Data, 0x03) => "coredump",
+            (PartitionType::Data, 0x04) => "nvs_keys",
+            (PartitionType::Data, 0x05) => "efuse",
+            (PartitionType::Data, 0x80) => "esphttpd",
+            (PartitionType::Data, 0x81) => "fat",
+            (PartitionType::Data, 0x82) => "spiffs",
+            (PartitionType::Data, 0x83) => "littlefs",
+            (PartitionType::App, subtype @ 0x10..=0x1f) => {
+                return format!("ota_{}", subtype - 0x10);
+            }
+            (_, subtype) => {
+                return format!("{subtype:#04x}");
+            }
+        };
+
+        name.to_owned()
+    }
+}
+
+impl PartitionTable {
+    pub fn parse(data: &[u8]) -> Result<Self, PartitionError> {
+        let mut partitions = Vec::new();
+
+        for (index, entry) in data.chunks(ENTRY_SIZE).enumerate() {
+            if entry.len() < ENTRY_SIZE {
+                return Err(PartitionError::Truncated);
+            }
+
+            let magic = [entry[0], entry[1]];
+
+            if magic == MD5_MAGIC {
+                let digest = Md5::digest(&data[..index * ENTRY_SIZE]);
+                if digest.as_slice() != &entry[16..32] {
+                    return Err(PartitionError::BadMd5);
+                }
+                continue;
+            }
+
+            if magic != ENTRY_MAGIC {
+                // end of table, the rest is erased flash
+                break;
+            }
+
+            let kind = match entry[2] {
+                0x00 => PartitionType::App,
+                0x01 => PartitionType::Data,
+                other => PartitionType::Other(other),
+            };
+
+            let label = &entry[12..28];
+            let label_len = label.iter().position(|b| *b == 0).unwrap_or(label.len());
+
+            partitions.push(Partition {
+                label: String::from_utf8_lossy(&label[..label_len]).into_owned(),
+                kind,
+                subtype: entry[3],
+                offset: u32::from_le_bytes(entry[4..8].try_into().expect("4 byte slice")),
+                size: u32::from_le_bytes(entry[8..12].try_into().expect("4 byte slice")),
+                flags: u32::from_le_bytes(entry[28..32].try_into().expect("4 byte slice")),
+            });
+        }
+
+        if partitions.is_empty() {
+            return Err(PartitionError::Empty);
+        }
+
+        Ok(PartitionTable { partitions })
+    }
+
+    pub fn find(&self, label: &str) -> Option<&Partition> {
+        self.partitions.iter().find(|partition| partition.label == label)
+    }
+
+    pub fn find_type(&self, kind: PartitionType, subtype: u8) -> Option<&Partition> {
+        self.partitions.iter().find(|partition| partition.kind == kind && partition.subtype == subtype)
+    }
+
+    pub fn containing(&self, addr: u32) -> Option<&Partition> {
+        self.partitions.iter().find(|partition| partition.contains(addr, 1))
+    }
+}
+
+/// Checks that every image lands inside flash, doesn't overlap any other
+/// image, and is inside a partition of the right type. Images before the
+/// end of the partition table (the bootloader and the table itself) aren't
+/// in any partition. Without a partition table, only the flash size and
+/// overlaps are checked.
+pub fn validate(
+    table: Option<&PartitionTable>,
+    images: &[LayoutImage],
+    flash_size: Option<u32>,
+) -> Result<(), LayoutError> {
+    let system_end = PARTITION_TABLE_ADDR + PARTITION_TABLE_SIZE;
+
+    for (index, image) in images.iter().enumerate() {
+        let Some(end) = image.addr.checked_add(image.len) else {
+            return Err(LayoutError::PastEndOfAddressSpace {
+                image: image.name.to_owned(),
+                addr: image.addr,
+            });
+        };
+
+        if let Some(flash_size) = flash_size {
+            if end > flash_size {
+                return Err(LayoutError::PastEndOfFlash {
+                    image: image.name.to_owned(),
+                    addr: image.addr,
+                    flash_size,
+                });
+            }
+        }
+
+        for other in &images[..index] {
+            let other_end = other.addr.saturating_add(other.len);
+            if image.addr < other_end && other.addr < end {
+                return Err(LayoutError::Overlap {
+                    image: image.name.to_owned(),
+                    other: other.name.to_owned(),
+                });
+            }
+        }
+
+        let Some(table) = table else {
+            continue;
+        };
+
+        if end <= system_end {
+            continue;
+        }
+
+        let partition = table.containing(image.addr)
+            .ok_or_else(|| LayoutError::NoPartition {
+                image: image.name.to_owned(),
+                addr: image.addr,
+            })?;
+
+        if !partition.contains(image.addr, image.len) {
+            return Err(LayoutError::OverrunsPartition {
+                image: image.name.to_owned(),
+                addr: image.addr,
+                partition: partition.label.clone(),
+            });
+        }
+
+        match (image.is_app, partition.kind) {
+            (true, PartitionType::App) => {}
+            (true, _) => {
+                return Err(LayoutError::AppInDataPartition {
+                    image: image.name.to_owned(),
+                    partition: partition.label.clone(),
+                });
+            }
+            (false, PartitionType::App) => {
+                return Err(LayoutError::DataInAppPartition {
+                    image: image.name.to_owned(),
+                    partition: partition.label.clone(),
+                });
+            }
+            (false, _) => {}
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,127 @@
+//! Detached signatures for firmware archives.
+//!
+//! A signed archive carries `tangaraflash.sig` alongside its manifest,
+//! holding an ed25519 signature over the exact bytes of
+//! `tangaraflash.json`. Only v1 manifests can be signed, because they list
+//! the SHA-256 of every image, so the signature covers the images too.
+
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+use super::{hex, parse_hex};
+
+pub const SIGNATURE_FILE: &str = "tangaraflash.sig";
+
+pub struct TrustedKey {
+    pub name: &'static str,
+    pub key: [u8; 32],
+}
+
+/// Keys trusted to sign firmware releases. Official release signing keys
+/// belong here. There aren't any yet, so nothing can be [`Trusted`]: every
+/// archive needs the user's say-so to flash, and signing is advisory only.
+///
+/// [`Trusted`]: Verification::Trusted
+pub static TRUSTED_KEYS: &[TrustedKey] = &[];
+
+#[derive(Deserialize, Serialize)]
+pub struct SignatureFile {
+    /// Hex encoded ed25519 public key
+    pub key: String,
+    /// Hex encoded ed25519 signature
+    pub signature: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verification {
+    /// Validly signed by one of the trusted keys
+    Trusted { key_name: &'static str },
+    /// Validly signed, but by a key we don't know
+    UntrustedKey { key: String },
+    /// Archive has no signature
+    Unsigned,
+    /// Archive has a signature, but it's malformed or doesn't match
+    Invalid(String),
+}
+
+impl Verification {
+    /// Whether the archive may be flashed at all. Invalid signatures are
+    /// always refused.
+    pub fn is_allowed(&self) -> bool {
+        !matches!(self, Verification::Invalid(_))
+    }
+
+    /// Whether the user has to explicitly agree before the archive is
+    /// flashed, because nothing vouches for where it came from.
+    pub fn needs_confirmation(&self) -> bool {
+        matches!(self, Verification::Unsigned | Verification::UntrustedKey { .. })
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Verification::Trusted { key_name } => format!("Signed by {key_name}"),
+            Verification::UntrustedKey { key } => format!("Signed by unknown key {key}"),
+            Verification::Unsigned => "Not signed".to_owned(),
+            Verification::Invalid(reason) => format!("Invalid signature: {reason}"),
+        }
+    }
+}
+
+/// Reads an ed25519 secret key, written as 64 hex digits.
+pub fn parse_signing_key(text: &str) -> Option<SigningKey> {
+    parse_hex::<32>(text.trim()).map(|bytes| SigningKey::from_bytes(&bytes))
+}
+
+/// Signs a manifest, returning the contents of its signature file.
+pub fn sign(manifest_json: &str, key: &SigningKey) -> String {
+    let file = SignatureFile {
+        key: hex(key.verifying_key().as_bytes()),
+        signature: hex(&key.sign(manifest_json.as_bytes()).to_bytes()),
+    };
+
+    serde_json::to_string_pretty(&file).expect("serialize signature")
+}
+
+pub fn verify(
+    manifest_json: &str,
+    manifest_version: usize,
+    signature: Option<&str>,
+    trusted: &[TrustedKey],
+) -> Verification {
+    let Some(signature) = signature else {
+        return Verification::Unsigned;
+    };
+
+    if manifest_version < 1 {
+        return Verification::Invalid("manifest has no image hashes".to_owned());
+    }
+
+    let file = match serde_json::from_str::<SignatureFile>(signature) {
+        Ok(file) => file,
+        Err(error) => { return Verification::Invalid(error.to_string()); }
+    };
+
+    let Some(key_bytes) = parse_hex::<32>(&file.key) else {
+        return Verification::Invalid("malformed public key".to_owned());
+    };
+
+    let Some(signature) = parse_hex::<64>(&file.signature) else {
+        return Verification::Invalid("malformed signature".to_owned());
+    };
+
+    let key = match VerifyingKey::from_bytes(&key_bytes) {
+        Ok(key) => key,
+        Err(error) => { return Verification::Invalid(error.to_string()); }
+    };
+
+    let signature = Signature::from_bytes(&signature);
+
+    if let Err(error) = key.verify_strict(manifest_json.as_bytes(), &signature) {
+        return Verification::Invalid(error.to_string());
+    }
+
+    match trusted.iter().find(|trusted| trusted.key == key_bytes) {
+        Some(trusted) => Verification::Trusted { key_name: trusted.name },
+        None => Verification::UntrustedKey { key: hex(&key_bytes) },
+    }
+}
@@ -1,4 +1,15 @@
+pub mod backup;
+pub mod erase;
+pub mod journal;
+pub mod preflight;
+mod status;
+
+use std::fs;
+use std::io;
+use std::ops::Range;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
 use espflash::{
     connection::{Connection, ResetAfterOperation, ResetBeforeOperation},
@@ -7,50 +18,145 @@ use espflash::{
     target::ProgressCallbacks,
 };
 use futures::channel::{mpsc, oneshot};
+use md5::Md5;
+use sha2::{Digest, Sha256};
 use mio_serial::FlowControl;
 use thiserror::Error;
 
 use crate::device::ConnectionParams;
-use crate::firmware::{Firmware, Image};
+use crate::firmware::{self, Firmware, Image, ReadImageError};
+use crate::firmware::partition::{self, PartitionError, PartitionTable};
+
+use self::journal::Journal;
+use self::preflight::PreflightReport;
+use self::status::Reporter;
+
+pub use self::status::{FlashPhase, FlashProgress, FlashStatus, FlashSummary, ImageOutcome, ImageSummary};
+
+/// Baud rates to try flashing at, fastest first. Some USB hubs and VMs
+/// can't keep up with the fastest, so a failed connect or write steps down
+/// to the next one.
+pub const BAUD_RATES: [u32; 4] = [1_000_000, 921_600, 460_800, 115_200];
+
+pub const DEFAULT_BAUD_RATE: u32 = BAUD_RATES[0];
+
+/// The ROM bootloader syncs at this rate before switching to the flash
+/// baud rate.
+const CONNECT_BAUD_RATE: u32 = 115_200;
 
-const BAUD_RATE: u32 = 1000000;
+/// Granularity of differential writes. This is the flash erase sector
+/// size, so it's the smallest region that can be rewritten on its own.
+const SECTOR_SIZE: usize = 0x1000;
 
-pub enum FlashStatus {
-    StartingFlash,
-    Image(String),
-    Progress(usize, usize),
+/// Images are written in pieces this big, so that a cancelled flash stops
+/// reasonably promptly. Sector aligned, so each piece erases cleanly.
+const WRITE_CHUNK_SIZE: usize = 0x40000;
+
+/// How many times to try writing each image before giving up
+const MAX_ATTEMPTS: u32 = 4;
+/// Delay before the first retry, doubling for each one after
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Controls how firmware is written to the device.
+#[derive(Clone, Debug)]
+pub struct FlashOptions {
+    /// Skip images whose flash region already matches, according to the
+    /// MD5 the device reports for it.
+    pub differential: bool,
+    /// Within images that have changed, only rewrite the sectors that
+    /// differ. Finding them costs round trips to the device for each
+    /// changed stretch, so it's only worth it for large images with small
+    /// changes.
+    pub skip_unchanged_sectors: bool,
+    /// Carry on from an interrupted flash of the same firmware, skipping
+    /// the images its journal says were already written.
+    pub resume: bool,
+    /// Baud rate to start flashing at, stepping down through
+    /// [`BAUD_RATES`] if it doesn't work out.
+    pub baud: u32,
+    /// Only write the images with these names, rather than all of them
+    pub only: Vec<String>,
+    /// Flash even if preflight checks find a problem with the device
+    pub force: bool,
+}
+
+impl Default for FlashOptions {
+    fn default() -> Self {
+        FlashOptions {
+            differential: true,
+            skip_unchanged_sectors: false,
+            resume: false,
+            baud: DEFAULT_BAUD_RATE,
+            only: Vec::new(),
+            force: false,
+        }
+    }
 }
 
 pub struct Flash {
     pub progress: mpsc::Receiver<FlashStatus>,
     pub result: oneshot::Receiver<Result<(), FlashError>>,
+    pub cancel: CancelToken,
 }
 
-pub fn setup(port: Arc<ConnectionParams>, firmware: Arc<Firmware>) -> (Flash, FlashTask) {
+/// Asks a running flash to stop. The flash stops at the next point where
+/// it's safe to, and finishes with [`FlashError::Cancelled`].
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub fn setup(
+    port: Arc<ConnectionParams>,
+    firmware: Arc<Firmware>,
+    options: FlashOptions,
+) -> (Flash, FlashTask) {
     let (progress_tx, progress) = mpsc::channel(32);
     let (result_tx, result) = oneshot::channel();
+    let cancel = CancelToken::default();
 
     let task = FlashTask {
         port,
         firmware,
+        options,
+        cancel: cancel.clone(),
+        preflight: PreflightReport::default(),
         progress_tx,
         result_tx,
     };
 
-    (Flash { progress, result }, task)
+    (Flash { progress, result, cancel }, task)
 }
 
 pub struct FlashTask {
     port: Arc<ConnectionParams>,
     firmware: Arc<Firmware>,
+    options: FlashOptions,
+    cancel: CancelToken,
+    preflight: PreflightReport,
     progress_tx: mpsc::Sender<FlashStatus>,
     result_tx: oneshot::Sender<Result<(), FlashError>>,
 }
 
 impl FlashTask {
+    /// Starts the preflight checks from what was found out over the
+    /// console, before the device went into its bootloader.
+    pub(crate) fn with_preflight(mut self, report: PreflightReport) -> Self {
+        self.preflight = report;
+        self
+    }
+
     /// Runs the flash - **blocks current thread**
     pub fn run(self) {
-        let result = run_flash(&self.port, &self.firmware, self.progress_tx);
+        let result = run_flash(&self.port, &self.firmware, &self.options, &self.cancel, self.preflight, self.progress_tx);
         let _ = self.result_tx.send(result);
     }
 }
@@ -61,30 +167,332 @@ pub enum FlashError {
     OpenSerial(#[from] mio_serial::Error),
     #[error("connecting to device: {0}")]
     Connect(#[source] espflash::Error),
+    #[error("reading image: {0}: {1}")]
+    ReadImage(String, #[source] ReadImageError),
     #[error("writing image: {0}: {1}")]
     WriteBin(String, #[source] espflash::Error),
+    #[error("verifying image: {0}: {1}")]
+    Verify(String, #[source] espflash::Error),
+    #[error("no image named {0} in firmware archive")]
+    NoSuchImage(String),
+    #[error("lost connection to device")]
+    Disconnected,
+    #[error("image {image} at {addr:#x} does not match what was written to flash")]
+    VerifyMismatch { image: String, addr: u32 },
+    /// The device may not boot until the remaining images are written
+    #[error("flash cancelled before writing {}", .remaining.join(", "))]
+    Cancelled { remaining: Vec<String> },
+    #[error("device failed preflight checks: {}", .0.summary())]
+    Preflight(PreflightReport),
+}
+
+impl FlashError {
+    /// Errors talking to the device, rather than problems with the
+    /// firmware itself. These might go away with a new connection.
+    fn is_connection_error(&self) -> bool {
+        matches!(self,
+            FlashError::OpenSerial(_) |
+            FlashError::Connect(_) |
+            FlashError::Disconnected |
+            FlashError::WriteBin(..) |
+            FlashError::Verify(..))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ReadFlashError {
+    #[error("reading flash: {0}")]
+    Flash(#[from] espflash::Error),
+    #[error("reading flash: {0}")]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Error)]
+pub enum ReadPartitionTableError {
+    #[error(transparent)]
+    Read(#[from] ReadFlashError),
+    #[error("reading partition table: {0}")]
+    Parse(#[from] PartitionError),
 }
 
 fn run_flash(
     port: &ConnectionParams,
     firmware: &Firmware,
-    mut sender: mpsc::Sender<FlashStatus>,
+    options: &FlashOptions,
+    cancel: &CancelToken,
+    report: PreflightReport,
+    sender: mpsc::Sender<FlashStatus>,
+) -> Result<(), FlashError> {
+    let images = select_images(firmware, &options.only)?;
+    let mut reporter = Reporter::start(sender, &images);
+
+    // check every image before touching the device, so a corrupt archive
+    // can't leave it half flashed
+    for image in &images {
+        image.verify()
+            .map_err(|error| FlashError::ReadImage(image.name.clone(), error))?;
+    }
+
+    // no point resetting the device into its bootloader if we already
+    // know we won't flash it
+    if !report.passed() && !options.force {
+        return Err(FlashError::Preflight(report));
+    }
+
+    let mut session = Session::connect(port, options.baud, &mut reporter)?;
+
+    let result = flash_images(&mut session, firmware, &images, options, cancel, report, &mut reporter);
+
+    // restart into the new firmware, or whatever was there already if
+    // flashing stopped early, rather than leaving it in the bootloader
+    if result.is_ok() {
+        reporter.phase(FlashPhase::Resetting);
+    }
+
+    session.reset();
+
+    if result.is_ok() {
+        reporter.finish();
+    }
+
+    result
+}
+
+/// Everything between connecting and resetting the device. Returning
+/// early for any reason still resets it.
+fn flash_images(
+    session: &mut Session,
+    firmware: &Firmware,
+    images: &[&Image],
+    options: &FlashOptions,
+    cancel: &CancelToken,
+    mut report: PreflightReport,
+    reporter: &mut Reporter,
 ) -> Result<(), FlashError> {
-    let _ = sender.try_send(FlashStatus::StartingFlash);
+    preflight::check_device(session.flasher()?, firmware, &mut report);
+    reporter.preflight(report.clone());
+
+    if !report.passed() {
+        if !options.force {
+            return Err(FlashError::Preflight(report));
+        }
+
+        log::warn!("flashing despite failed preflight checks: {}", report.summary());
+    }
+
+    // a partial flash isn't worth resuming, and shouldn't clobber the
+    // journal of a full one
+    let mut journal = if options.only.is_empty() {
+        open_journal(session.port, firmware, options.resume)
+    } else {
+        None
+    };
+
+    for (index, image) in images.iter().enumerate() {
+        reporter.start_image(index, images.len(), image);
+
+        let data = image.read()
+            .map_err(|error| FlashError::ReadImage(image.name.clone(), error))?;
+
+        let sha256 = firmware::hex(&Sha256::digest(&data));
+
+        if journal.as_ref().is_some_and(|journal| journal.is_complete(image, &sha256)) {
+            reporter.finish_image(ImageOutcome::Skipped);
+            continue;
+        }
+
+        let result = if cancel.is_cancelled() {
+            Err(FlashError::Cancelled { remaining: Vec::new() })
+        } else {
+            flash_with_retry(session, image, &data, options, cancel, reporter)
+        };
+
+        if let Err(FlashError::Cancelled { .. }) = result {
+            let remaining = images[index..].iter()
+                .map(|image| image.name.clone())
+                .collect();
+
+            return Err(FlashError::Cancelled { remaining });
+        }
+
+        reporter.finish_image(result?);
+
+        if let Some(journal) = &mut journal {
+            if let Err(error) = journal.record(image, sha256) {
+                log::warn!("recording {} in flash journal: {error}", image.name);
+            }
+        }
+    }
 
-    for image in firmware.images() {
-        flash_image(&port, &image, &sender)?;
+    if let Some(journal) = journal {
+        if let Err(error) = journal.finish() {
+            log::warn!("removing flash journal: {error}");
+        }
     }
 
     Ok(())
 }
 
+/// The images to write, in archive order
+fn select_images<'a>(firmware: &'a Firmware, only: &[String]) -> Result<Vec<&'a Image>, FlashError> {
+    let images = firmware.images();
+
+    if let Some(missing) = only.iter().find(|name| !images.iter().any(|image| image.name == **name)) {
+        return Err(FlashError::NoSuchImage(missing.clone()));
+    }
+
+    Ok(images.iter()
+        .filter(|image| only.is_empty() || only.contains(&image.name))
+        .collect())
+}
+
+/// Picks up the journal of an interrupted flash of the same firmware when
+/// resuming, otherwise starts a fresh one. The journal is only a
+/// convenience, so problems with it are logged rather than failing the
+/// flash.
+fn open_journal(port: &ConnectionParams, firmware: &Firmware, resume: bool) -> Option<Journal> {
+    if resume {
+        match Journal::load(port) {
+            Ok(Some(journal)) if journal.matches(firmware) => { return Some(journal); }
+            Ok(_) => log::info!("no interrupted flash of {} to resume", firmware.version()),
+            Err(error) => log::warn!("reading flash journal: {error}"),
+        }
+    }
+
+    Journal::start(port, firmware)
+        .map_err(|error| log::warn!("starting flash journal: {error}"))
+        .ok()
+}
+
+/// A connection to the device for the length of a flash, which is
+/// re-established if it drops out.
+struct Session<'a> {
+    port: &'a ConnectionParams,
+    /// `None` after a reconnect failed
+    flasher: Option<Flasher>,
+    baud: u32,
+}
+
+impl<'a> Session<'a> {
+    fn connect(port: &'a ConnectionParams, baud: u32, reporter: &mut Reporter)
+        -> Result<Self, FlashError>
+    {
+        let (flasher, baud) = connect_with_fallback(port, baud, reporter)?;
+        Ok(Session { port, flasher: Some(flasher), baud })
+    }
+
+    fn flasher(&mut self) -> Result<&mut Flasher, FlashError> {
+        self.flasher.as_mut().ok_or(FlashError::Disconnected)
+    }
+
+    /// Resets the device out of the bootloader, if we're still connected
+    /// to it.
+    fn reset(&mut self) {
+        let Some(flasher) = &mut self.flasher else {
+            log::warn!("not resetting device, connection to it was lost");
+            return;
+        };
+
+        if let Err(error) = flasher.connection().reset() {
+            log::warn!("resetting device: {error}");
+        }
+    }
+
+    /// Starts a fresh connection, a step slower than the last one in case
+    /// the baud rate is what the trouble was.
+    fn reconnect(&mut self, reporter: &mut Reporter) -> Result<(), FlashError> {
+        let baud = slower_baud(self.baud).unwrap_or(self.baud);
+
+        // serial ports are opened exclusively, so the old connection has to
+        // be closed before a new one can open. if reconnecting fails, the
+        // session stays disconnected
+        self.flasher = None;
+
+        let (flasher, baud) = connect_with_fallback(self.port, baud, reporter)?;
+        self.flasher = Some(flasher);
+        self.baud = baud;
+        Ok(())
+    }
+}
+
+/// Connects at `baud`, stepping down to slower rates until one works.
+/// Returns the rate that worked too.
+fn connect_with_fallback(port: &ConnectionParams, baud: u32, reporter: &mut Reporter)
+    -> Result<(Flasher, u32), FlashError>
+{
+    reporter.phase(FlashPhase::Connecting);
+    let (flasher, baud) = connect_flasher_fallback(port, baud)?;
+    reporter.connected(baud);
+    Ok((flasher, baud))
+}
+
+/// Connects to the bootloader at `baud`, stepping down through
+/// [`BAUD_RATES`] until one works. Returns the rate it settled on.
+pub fn connect_flasher_fallback(port: &ConnectionParams, mut baud: u32)
+    -> Result<(Flasher, u32), FlashError>
+{
+    loop {
+        // detect the chip rather than assuming it, for preflight checks
+        match open_flasher(port, None, baud) {
+            Ok(flasher) => return Ok((flasher, baud)),
+            Err(error) if error.is_connection_error() => {
+                let Some(slower) = slower_baud(baud) else { return Err(error) };
+                log::warn!("connecting at {baud} baud failed, trying {slower}: {error}");
+                baud = slower;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// The next rate down from `baud` in [`BAUD_RATES`]
+fn slower_baud(baud: u32) -> Option<u32> {
+    BAUD_RATES.into_iter().find(|&rate| rate < baud)
+}
+
+/// Flashes an image, reconnecting and trying again with increasing delays
+/// if talking to the device fails part way through.
+fn flash_with_retry(
+    session: &mut Session,
+    image: &Image,
+    data: &[u8],
+    options: &FlashOptions,
+    cancel: &CancelToken,
+    reporter: &mut Reporter,
+) -> Result<ImageOutcome, FlashError> {
+    let mut attempt = 1;
+    let mut result = session.flasher()
+        .and_then(|flasher| flash_image(flasher, image, data, options, cancel, reporter));
+
+    while let Err(error) = &result {
+        if !error.is_connection_error() || attempt == MAX_ATTEMPTS {
+            break;
+        }
+
+        log::warn!("flashing {} failed (attempt {attempt}/{MAX_ATTEMPTS}): {error}", image.name);
+
+        reporter.retrying(attempt);
+
+        std::thread::sleep(RETRY_BACKOFF * 2u32.pow(attempt - 1));
+        attempt += 1;
+
+        if cancel.is_cancelled() {
+            return Err(FlashError::Cancelled { remaining: Vec::new() });
+        }
+
+        // the session may have dropped out from under us
+        result = session.reconnect(reporter).and_then(|()| {
+            flash_image(session.flasher()?, image, data, options, cancel, reporter)
+        });
+    }
+
+    result
+}
+
 pub fn open_flash_connection(port: &ConnectionParams)
     -> Result<espflash::connection::Connection, mio_serial::Error>
 {
-    let connection_baud = 115_200;
-
-    let serial = mio_serial::new(&port.serial.port_name, connection_baud)
+    let serial = mio_serial::new(&port.serial.port_name, CONNECT_BAUD_RATE)
         .flow_control(FlowControl::None)
         .open_native()?;
 
@@ -93,65 +501,234 @@ pub fn open_flash_connection(port: &ConnectionParams)
         port.usb.clone(),
         ResetAfterOperation::HardReset,
         ResetBeforeOperation::DefaultReset,
-        connection_baud,
+        CONNECT_BAUD_RATE,
     ))
 }
 
-fn flash_image(
-    port: &ConnectionParams,
-    image: &Image,
-    sender: &mpsc::Sender<FlashStatus>,
-) -> Result<(), FlashError> {
+pub fn connect_flasher(port: &ConnectionParams) -> Result<Flasher, FlashError> {
+    connect_flasher_at(port, DEFAULT_BAUD_RATE)
+}
+
+pub fn connect_flasher_at(port: &ConnectionParams, baud: u32) -> Result<Flasher, FlashError> {
+    open_flasher(port, Some(Chip::Esp32), baud)
+}
+
+fn open_flasher(port: &ConnectionParams, chip: Option<Chip>, baud: u32) -> Result<Flasher, FlashError> {
     let connection = open_flash_connection(port)?;
 
-    let mut flasher = Flasher::connect(
+    Flasher::connect(
         connection,
         true,
         false,
         false,
-        Some(Chip::Esp32),
-        Some(BAUD_RATE),
+        chip,
+        Some(baud),
     )
-    .map_err(FlashError::Connect)?;
+    .map_err(FlashError::Connect)
+}
+
+/// Checks that there's an ESP32 ROM bootloader on the other end of a USB
+/// serial bridge, which could otherwise be attached to anything, then
+/// resets it. Returns the chip it found.
+pub async fn probe_bootloader(port: &ConnectionParams) -> Result<Chip, FlashError> {
+    let (tx, rx) = oneshot::channel();
+    let port = port.clone();
+    std::thread::spawn(move || {
+        let _ = tx.send(probe_blocking(&port));
+    });
+    rx.await.unwrap()
+}
+
+fn probe_blocking(port: &ConnectionParams) -> Result<Chip, FlashError> {
+    let mut flasher = open_flasher(port, None, CONNECT_BAUD_RATE)?;
+    let chip = flasher.chip();
+
+    if let Err(error) = flasher.connection().reset() {
+        log::warn!("resetting device after probing: {error}");
+    }
+
+    Ok(chip)
+}
+
+/// Reads a region of flash into memory. espflash can only read flash out
+/// to a file, so this goes via a temporary file.
+pub fn read_flash(flasher: &mut Flasher, addr: u32, size: u32)
+    -> Result<Vec<u8>, ReadFlashError>
+{
+    const BLOCK_SIZE: u32 = 0x1000;
+    const MAX_IN_FLIGHT: u32 = 64;
+
+    let path = std::env::temp_dir()
+        .join(format!("tangara-read-{}-{addr:x}.bin", std::process::id()));
+
+    let result = flasher.read_flash(addr, size, BLOCK_SIZE, MAX_IN_FLIGHT, path.clone());
+    let data = result.map_err(ReadFlashError::from).and_then(|()| Ok(fs::read(&path)?));
+    let _ = fs::remove_file(&path);
+    data
+}
+
+/// Reads and parses the partition table the device is currently using.
+pub fn read_partition_table(flasher: &mut Flasher)
+    -> Result<PartitionTable, ReadPartitionTableError>
+{
+    let data = read_flash(flasher, partition::PARTITION_TABLE_ADDR, partition::PARTITION_TABLE_SIZE)?;
+    Ok(PartitionTable::parse(&data)?)
+}
+
+fn flash_image(
+    flasher: &mut Flasher,
+    image: &Image,
+    data: &[u8],
+    options: &FlashOptions,
+    cancel: &CancelToken,
+    reporter: &mut Reporter,
+) -> Result<ImageOutcome, FlashError> {
+    if options.differential && region_matches(flasher, image, image.addr, data)? {
+        return Ok(ImageOutcome::Skipped);
+    }
+
+    let ranges = if options.differential && options.skip_unchanged_sectors {
+        // the whole image was just compared above, so we know it differs
+        let mut ranges = Vec::new();
+        changed_sectors(flasher, image, data, 0..data.len(), true, &mut ranges)?;
+        ranges
+    } else {
+        vec![Range { start: 0, end: data.len() }]
+    };
+
+    // erasing happens as part of each write, before espflash starts
+    // reporting progress for it
+    reporter.phase(FlashPhase::Erasing);
 
     let mut progress = ProgressCallback {
-        image: image.name.clone(),
+        reporter,
+        chunk: 0..0,
         total: 0,
-        sender: sender.clone(),
     };
 
-    flasher
-        .write_bin_to_flash(image.addr, &image.data, &mut progress)
-        .map_err(|error| FlashError::WriteBin(image.name.clone(), error))?;
+    let chunks = ranges.into_iter().flat_map(|range| {
+        range.clone().step_by(WRITE_CHUNK_SIZE)
+            .map(move |start| start..range.end.min(start + WRITE_CHUNK_SIZE))
+    });
+
+    for chunk in chunks {
+        if cancel.is_cancelled() {
+            return Err(FlashError::Cancelled { remaining: Vec::new() });
+        }
+
+        let addr = image.addr + chunk.start as u32;
+        progress.chunk = chunk.clone();
+
+        flasher
+            .write_bin_to_flash(addr, &data[chunk], &mut progress)
+            .map_err(|error| FlashError::WriteBin(image.name.clone(), error))?;
+    }
+
+    progress.verifying();
+    verify_image(flasher, image, data)?;
+
+    Ok(ImageOutcome::Written)
+}
+
+/// Finds the runs of sectors within `range` of an image that differ from
+/// what's on flash, adding them to `ranges` as byte ranges into the image.
+/// Returns whether any did.
+///
+/// Each MD5 costs a round trip to the device, so rather than checking every
+/// sector, this halves the range until it finds the sectors that differ.
+/// Unchanged stretches are ruled out in one go, and when the first half
+/// matches, the second half must be what changed without asking.
+fn changed_sectors(
+    flasher: &mut Flasher,
+    image: &Image,
+    data: &[u8],
+    range: Range<usize>,
+    known_changed: bool,
+    ranges: &mut Vec<Range<usize>>,
+) -> Result<bool, FlashError> {
+    if !known_changed && region_matches(flasher, image, image.addr + range.start as u32, &data[range.clone()])? {
+        return Ok(false);
+    }
+
+    if range.len() <= SECTOR_SIZE {
+        match ranges.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => ranges.push(range),
+        }
+        return Ok(true);
+    }
+
+    // split on a sector boundary, with at least one sector each side
+    let sectors = range.len().div_ceil(SECTOR_SIZE);
+    let mid = range.start + sectors / 2 * SECTOR_SIZE;
+
+    let first_changed = changed_sectors(flasher, image, data, range.start..mid, false, ranges)?;
+    changed_sectors(flasher, image, data, mid..range.end, !first_changed, ranges)?;
+
+    Ok(true)
+}
+
+/// Whether the flash at `addr` already holds `data`.
+fn region_matches(flasher: &mut Flasher, image: &Image, addr: u32, data: &[u8])
+    -> Result<bool, FlashError>
+{
+    let (len, expected) = padded_md5(data);
+
+    let actual = flasher.checksum_md5(addr, len)
+        .map_err(|error| FlashError::Verify(image.name.clone(), error))?;
+
+    Ok(actual == expected)
+}
+
+/// Length and MD5 of `data` as it ends up on flash: writes are padded out
+/// to a whole word with erased flash.
+fn padded_md5(data: &[u8]) -> (u32, u128) {
+    let mut padded = data.to_vec();
+    padded.resize(data.len().next_multiple_of(4), 0xff);
+    (padded.len() as u32, u128::from_be_bytes(Md5::digest(&padded).into()))
+}
+
+/// Compares the MD5 of the flash region an image was written to with the
+/// image itself.
+fn verify_image(flasher: &mut Flasher, image: &Image, data: &[u8]) -> Result<(), FlashError> {
+    if !region_matches(flasher, image, image.addr, data)? {
+        return Err(FlashError::VerifyMismatch {
+            image: image.name.clone(),
+            addr: image.addr,
+        });
+    }
 
     Ok(())
 }
 
-struct ProgressCallback {
-    image: String,
+/// Reports progress through an image in bytes. espflash reports it in
+/// blocks of compressed data for each write, and an image may be written
+/// in several pieces.
+struct ProgressCallback<'a> {
+    reporter: &'a mut Reporter,
+    /// The part of the image currently being written
+    chunk: Range<usize>,
+    /// Blocks in the current write
     total: usize,
-    sender: mpsc::Sender<FlashStatus>,
 }
 
-impl ProgressCallbacks for ProgressCallback {
+impl ProgressCallbacks for ProgressCallback<'_> {
     fn init(&mut self, _: u32, total: usize) {
         self.total = total;
-        let status = FlashStatus::Image(self.image.clone());
-        let _ = self.sender.try_send(status);
+        self.reporter.phase(FlashPhase::Writing);
+        self.reporter.start_write(self.chunk.start as u64);
     }
 
     fn update(&mut self, current: usize) {
-        let status = FlashStatus::Progress(current, self.total);
-        // use try_send here because it's ok if we drop a message
-        let _ = self.sender.try_send(status);
+        let written = self.chunk.len() * current / self.total.max(1);
+        self.reporter.progress((self.chunk.start + written) as u64);
     }
 
     fn finish(&mut self, _skipped: bool) {
-        let status = FlashStatus::Progress(self.total, self.total);
-        let _ = self.sender.try_send(status);
+        self.reporter.progress(self.chunk.end as u64);
     }
 
     fn verifying(&mut self) {
-        todo!()
+        self.reporter.phase(FlashPhase::Verifying);
     }
 }
@@ -0,0 +1,200 @@
+//! Reading a device's flash back into a firmware archive, so it can be
+//! restored later through the usual flash path.
+//!
+//! A full backup is one `flash.bin` image covering the whole chip. A
+//! partition backup has an image per partition, named after its label.
+
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use espflash::flasher::Flasher;
+use futures::channel::{mpsc, oneshot};
+use thiserror::Error;
+
+use crate::device::ConnectionParams;
+use crate::firmware::builder::{BuildError, Builder};
+
+use super::{FlashError, ReadFlashError, ReadPartitionTableError};
+
+/// Flash is read in pieces this big, to report progress in between.
+const READ_CHUNK_SIZE: u32 = 0x100000;
+
+/// Name of the image in a full backup
+pub const FULL_IMAGE: &str = "flash.bin";
+
+#[derive(Clone, Debug)]
+pub enum BackupTarget {
+    /// Everything on the flash chip
+    Full,
+    /// Partitions from the device's partition table, by label
+    Partitions(Vec<String>),
+}
+
+impl BackupTarget {
+    /// Checks the target makes sense before connecting to the device. Each
+    /// partition becomes an image named after it, so they must be unique.
+    pub fn check(&self) -> Result<(), BackupError> {
+        if let BackupTarget::Partitions(labels) = self {
+            for (index, label) in labels.iter().enumerate() {
+                if labels[..index].contains(label) {
+                    return Err(BackupError::DuplicatePartition(label.clone()));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum BackupStatus {
+    /// Connected to the bootloader at this baud rate
+    Connected { baud: u32 },
+    /// Connected, and about to read this many bytes in total
+    Starting { total: u64 },
+    /// Bytes read so far across all images
+    Progress { image: String, read: u64, total: u64 },
+    /// Writing the archive
+    Packing,
+}
+
+#[derive(Debug, Error)]
+pub enum BackupError {
+    #[error(transparent)]
+    Connect(#[from] FlashError),
+    #[error("detecting flash size: {0}")]
+    DeviceInfo(#[source] espflash::Error),
+    #[error(transparent)]
+    PartitionTable(#[from] ReadPartitionTableError),
+    #[error("no partition named {0} in device partition table")]
+    NoPartition(String),
+    #[error("partition {0} given more than once")]
+    DuplicatePartition(String),
+    #[error(transparent)]
+    Read(#[from] ReadFlashError),
+    #[error("writing backup: {0}")]
+    Build(#[from] BuildError),
+    #[error("backup thread terminated unexpectedly")]
+    Canceled(#[from] oneshot::Canceled),
+}
+
+/// Reads the device's flash into an archive at `path`, then resets the
+/// device. Any open console connection to the device must be disconnected
+/// first. Slower rates than `baud` are tried if it doesn't work out.
+pub async fn backup(
+    params: &ConnectionParams,
+    target: BackupTarget,
+    path: &Path,
+    baud: u32,
+    progress: mpsc::Sender<BackupStatus>,
+) -> Result<(), BackupError> {
+    target.check()?;
+
+    let (tx, rx) = oneshot::channel();
+    let params = params.clone();
+    let path = path.to_owned();
+    std::thread::spawn(move || {
+        let _ = tx.send(backup_blocking(&params, &target, &path, baud, progress));
+    });
+    rx.await?
+}
+
+struct Region {
+    name: String,
+    addr: u32,
+    size: u32,
+}
+
+fn backup_blocking(
+    params: &ConnectionParams,
+    target: &BackupTarget,
+    path: &Path,
+    baud: u32,
+    mut progress: mpsc::Sender<BackupStatus>,
+) -> Result<(), BackupError> {
+    let (mut flasher, baud) = super::connect_flasher_fallback(params, baud)?;
+    let _ = progress.try_send(BackupStatus::Connected { baud });
+
+    let result = read_backup(&mut flasher, target, path, progress);
+
+    if let Err(error) = flasher.connection().reset() {
+        log::warn!("resetting device after backup: {error}");
+    }
+
+    result
+}
+
+fn read_backup(
+    flasher: &mut Flasher,
+    target: &BackupTarget,
+    path: &Path,
+    mut progress: mpsc::Sender<BackupStatus>,
+) -> Result<(), BackupError> {
+    let regions = match target {
+        BackupTarget::Full => {
+            let info = flasher.device_info().map_err(BackupError::DeviceInfo)?;
+            vec![Region { name: FULL_IMAGE.to_owned(), addr: 0, size: info.flash_size.size() }]
+        }
+        BackupTarget::Partitions(labels) => {
+            let table = super::read_partition_table(flasher)?;
+
+            labels.iter()
+                .map(|label| {
+                    let partition = table.find(label)
+                        .ok_or_else(|| BackupError::NoPartition(label.clone()))?;
+
+                    Ok(Region {
+                        name: format!("{label}.bin"),
+                        addr: partition.offset,
+                        size: partition.size,
+                    })
+                })
+                .collect::<Result<Vec<_>, BackupError>>()?
+        }
+    };
+
+    let total = regions.iter().map(|region| u64::from(region.size)).sum();
+    let mut read = 0;
+
+    let _ = progress.try_send(BackupStatus::Starting { total });
+
+    let taken = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+
+    let notes = match target {
+        BackupTarget::Full => "Backup of the whole flash chip read from a Tangara.".to_owned(),
+        BackupTarget::Partitions(labels) => {
+            format!("Backup of partitions {} read from a Tangara.", labels.join(", "))
+        }
+    };
+
+    let mut builder = Builder::new()
+        .version(format!("backup-{taken}"))
+        .release_notes(notes);
+
+    for region in regions {
+        let mut data = Vec::with_capacity(region.size as usize);
+
+        for offset in (0..region.size).step_by(READ_CHUNK_SIZE as usize) {
+            let size = READ_CHUNK_SIZE.min(region.size - offset);
+            data.extend(super::read_flash(flasher, region.addr + offset, size)?);
+
+            read += u64::from(size);
+            // use try_send here because it's ok if we drop a message
+            let _ = progress.try_send(BackupStatus::Progress {
+                image: region.name.clone(),
+                read,
+                total,
+            });
+        }
+
+        builder = builder.image(region.name, region.addr, data);
+    }
+
+    let _ = progress.try_send(BackupStatus::Packing);
+    builder.write(path)?;
+
+    Ok(())
+}
@@ -0,0 +1,87 @@
+//! Erasing flash, for recovering devices whose flash is in too bad a state
+//! to just write new firmware over.
+
+use espflash::flasher::Flasher;
+use futures::channel::oneshot;
+use thiserror::Error;
+
+use crate::device::ConnectionParams;
+
+use super::{FlashError, ReadPartitionTableError, SECTOR_SIZE};
+
+#[derive(Clone, Debug)]
+pub enum EraseTarget {
+    /// The whole flash chip, firmware and all
+    Chip,
+    /// A partition from the partition table currently on the device
+    Partition(String),
+    /// A range of flash, which must be whole sectors
+    Region { addr: u32, size: u32 },
+}
+
+#[derive(Debug, Error)]
+pub enum EraseError {
+    #[error(transparent)]
+    Connect(#[from] FlashError),
+    #[error(transparent)]
+    PartitionTable(#[from] ReadPartitionTableError),
+    #[error("no partition named {0} in device partition table")]
+    NoPartition(String),
+    #[error("region {addr:#x}, size {size:#x} is not whole {:#x} byte sectors", SECTOR_SIZE)]
+    Unaligned { addr: u32, size: u32 },
+    #[error("region {addr:#x}, size {size:#x} is empty or runs past the end of flash")]
+    BadRegion { addr: u32, size: u32 },
+    #[error("erasing flash: {0}")]
+    Erase(#[source] espflash::Error),
+    #[error("erase thread terminated unexpectedly")]
+    Canceled(#[from] oneshot::Canceled),
+}
+
+/// Erases part or all of the device's flash, then resets it. Any open
+/// console connection to the device must be disconnected first. Slower
+/// rates than `baud` are tried if it doesn't work out.
+pub async fn erase(params: &ConnectionParams, target: EraseTarget, baud: u32) -> Result<(), EraseError> {
+    let (tx, rx) = oneshot::channel();
+    let params = params.clone();
+    std::thread::spawn(move || {
+        let _ = tx.send(erase_blocking(&params, &target, baud));
+    });
+    rx.await?
+}
+
+fn erase_blocking(params: &ConnectionParams, target: &EraseTarget, baud: u32) -> Result<(), EraseError> {
+    let (mut flasher, _) = super::connect_flasher_fallback(params, baud)?;
+    let result = erase_target(&mut flasher, target);
+
+    if let Err(error) = flasher.connection().reset() {
+        log::warn!("resetting device after erasing: {error}");
+    }
+
+    result
+}
+
+fn erase_target(flasher: &mut Flasher, target: &EraseTarget) -> Result<(), EraseError> {
+    let (addr, size) = match target {
+        EraseTarget::Chip => {
+            return flasher.erase_flash().map_err(EraseError::Erase);
+        }
+        EraseTarget::Partition(label) => {
+            let table = super::read_partition_table(flasher)?;
+            let partition = table.find(label)
+                .ok_or_else(|| EraseError::NoPartition(label.clone()))?;
+            (partition.offset, partition.size)
+        }
+        EraseTarget::Region { addr, size } => (*addr, *size),
+    };
+
+    if size == 0 || addr.checked_add(size).is_none() {
+        return Err(EraseError::BadRegion { addr, size });
+    }
+
+    let sector = SECTOR_SIZE as u32;
+    if addr % sector != 0 || size % sector != 0 {
+        return Err(EraseError::Unaligned { addr, size });
+    }
+
+    flasher.erase_region(addr, size).map_err(EraseError::Erase)
+}
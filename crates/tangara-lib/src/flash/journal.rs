@@ -0,0 +1,158 @@
+//! A record of which images a flash has finished writing, so that one
+//! which was interrupted part way through can be resumed without rewriting
+//! everything.
+//!
+//! There's one journal per device, kept in the user's state directory. It's
+//! removed once a flash completes.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::device::ConnectionParams;
+use crate::firmware::{Firmware, Image};
+
+const JOURNAL_SUBDIR: &str = "tangara-companion/flash-journal";
+
+#[derive(Debug, Error)]
+pub enum JournalError {
+    #[error("no state directory on this system")]
+    NoStateDir,
+    #[error("flash journal: {0}")]
+    Io(#[from] io::Error),
+    #[error("flash journal: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CompletedImage {
+    pub name: String,
+    pub addr: u32,
+    /// Hex SHA-256 of the image as written
+    pub sha256: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    firmware_version: String,
+    /// When the flash started, in seconds since the Unix epoch
+    started: u64,
+    completed: Vec<CompletedImage>,
+}
+
+pub struct Journal {
+    path: PathBuf,
+    record: Record,
+}
+
+impl Journal {
+    /// Loads the journal left behind by an interrupted flash of this
+    /// device, if there is one.
+    pub fn load(port: &ConnectionParams) -> Result<Option<Journal>, JournalError> {
+        let path = journal_path(port)?;
+
+        let record = match fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => { return Ok(None); }
+            Err(error) => { return Err(error.into()); }
+        };
+
+        Ok(Some(Journal { path, record }))
+    }
+
+    /// Starts a new journal for flashing `firmware` to this device,
+    /// replacing any existing one.
+    pub fn start(port: &ConnectionParams, firmware: &Firmware) -> Result<Journal, JournalError> {
+        let journal = Journal {
+            path: journal_path(port)?,
+            record: Record {
+                firmware_version: firmware.version().to_owned(),
+                started: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|duration| duration.as_secs())
+                    .unwrap_or_default(),
+                completed: Vec::new(),
+            },
+        };
+
+        journal.write()?;
+        Ok(journal)
+    }
+
+    pub fn firmware_version(&self) -> &str {
+        &self.record.firmware_version
+    }
+
+    pub fn completed(&self) -> &[CompletedImage] {
+        &self.record.completed
+    }
+
+    /// Whether this journal is for a flash of `firmware`, and so can be
+    /// used to resume it.
+    pub fn matches(&self, firmware: &Firmware) -> bool {
+        self.record.firmware_version == firmware.version()
+    }
+
+    /// Whether an image with this hash was already written at the same
+    /// address.
+    pub fn is_complete(&self, image: &Image, sha256: &str) -> bool {
+        self.record.completed.iter().any(|completed| {
+            completed.name == image.name
+                && completed.addr == image.addr
+                && completed.sha256 == sha256
+        })
+    }
+
+    pub fn record(&mut self, image: &Image, sha256: String) -> Result<(), JournalError> {
+        self.record.completed.retain(|completed| completed.name != image.name);
+        self.record.completed.push(CompletedImage {
+            name: image.name.clone(),
+            addr: image.addr,
+            sha256,
+        });
+
+        self.write()
+    }
+
+    /// Removes the journal once there's nothing left to resume.
+    pub fn finish(self) -> Result<(), JournalError> {
+        match fs::remove_file(&self.path) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn write(&self) -> Result<(), JournalError> {
+        let json = serde_json::to_vec_pretty(&self.record)?;
+
+        let partial = self.path.with_extension("json.partial");
+        fs::write(&partial, json)?;
+        fs::rename(&partial, &self.path)?;
+
+        Ok(())
+    }
+}
+
+/// Journals are named after the device's USB serial number, falling back
+/// to its port name for devices that don't have one.
+fn journal_path(port: &ConnectionParams) -> Result<PathBuf, JournalError> {
+    let dir = dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .ok_or(JournalError::NoStateDir)?
+        .join(JOURNAL_SUBDIR);
+
+    fs::create_dir_all(&dir)?;
+
+    let device = port.usb.serial_number.as_deref()
+        .unwrap_or(&port.serial.port_name);
+
+    let name = device.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+
+    Ok(dir.join(format!("{name}.json")))
+}
@@ -0,0 +1,134 @@
+//! Checks that a device is fit to be flashed with a firmware archive,
+//! before anything is written to it.
+//!
+//! Battery and hardware revision come from the console, so they're only
+//! known when the device is running firmware; the chip and flash size come
+//! from the ROM bootloader once connected. Anything we couldn't find out
+//! is left as `None` rather than counted as a problem.
+
+use espflash::flasher::Flasher;
+use espflash::target::Chip;
+use thiserror::Error;
+
+use crate::device::Connection;
+use crate::firmware::Firmware;
+use crate::firmware::app_image;
+
+/// Below this, a flash is refused in case the battery dies part way
+/// through and leaves the device unbootable.
+pub const MIN_BATTERY_PERCENT: u8 = 20;
+
+#[derive(Clone, Debug, Default)]
+pub struct PreflightReport {
+    pub battery_percent: Option<u8>,
+    pub hardware_revision: Option<String>,
+    pub chip: Option<Chip>,
+    /// Size of the device's flash chip in bytes
+    pub flash_size: Option<u32>,
+    pub problems: Vec<PreflightProblem>,
+}
+
+#[derive(Clone, Debug, Error)]
+pub enum PreflightProblem {
+    #[error("battery is at {0}%, charge it to at least {MIN_BATTERY_PERCENT}% first")]
+    LowBattery(u8),
+    #[error("firmware is for hardware revision {firmware}, but device is {device}")]
+    WrongHardware { device: String, firmware: String },
+    #[error("firmware is for {firmware}, but device is {device}")]
+    WrongChip { device: Chip, firmware: Chip },
+    #[error("firmware needs {required:#x} bytes of flash, but device only has {device:#x}")]
+    FlashTooSmall { device: u32, required: u32 },
+}
+
+impl PreflightReport {
+    /// Whether it's safe to go ahead and flash
+    pub fn passed(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Every problem, in one line
+    pub fn summary(&self) -> String {
+        self.problems.iter()
+            .map(|problem| problem.to_string())
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+}
+
+/// Asks the running firmware for its battery level and hardware revision.
+/// This has to happen before the device is reset into its bootloader.
+pub async fn check_console(conn: &Connection, firmware: &Firmware) -> PreflightReport {
+    let battery_percent = conn.eval_lua("require('power').battery_pct:get()").await
+        .ok()
+        .and_then(|percent| percent.trim().parse().ok());
+
+    // older firmware has no way to ask
+    let hardware_revision = conn
+        .eval_lua("(require('version').hardware or function() return '' end)()").await
+        .ok()
+        .map(|revision| revision.trim().to_owned())
+        .filter(|revision| !revision.is_empty());
+
+    let mut report = PreflightReport {
+        battery_percent,
+        hardware_revision,
+        ..PreflightReport::default()
+    };
+
+    if let Some(percent) = report.battery_percent {
+        if percent < MIN_BATTERY_PERCENT {
+            report.problems.push(PreflightProblem::LowBattery(percent));
+        }
+    }
+
+    if let (Some(device), Some(firmware)) = (&report.hardware_revision, firmware.hardware_revision()) {
+        if !device.eq_ignore_ascii_case(firmware) {
+            report.problems.push(PreflightProblem::WrongHardware {
+                device: device.clone(),
+                firmware: firmware.to_owned(),
+            });
+        }
+    }
+
+    report
+}
+
+/// Checks the chip the bootloader reports, and the size of its flash,
+/// against what the archive was built for.
+pub(super) fn check_device(flasher: &mut Flasher, firmware: &Firmware, report: &mut PreflightReport) {
+    let chip = flasher.chip();
+    report.chip = Some(chip);
+
+    if let Some(expected) = firmware.chip_id().and_then(chip_from_id) {
+        if chip != expected {
+            report.problems.push(PreflightProblem::WrongChip { device: chip, firmware: expected });
+        }
+    }
+
+    match flasher.device_info() {
+        Ok(info) => {
+            let size = info.flash_size.size();
+            report.flash_size = Some(size);
+
+            let required = firmware.flash_size().unwrap_or_default().max(firmware.flash_end());
+            if size < required {
+                report.problems.push(PreflightProblem::FlashTooSmall { device: size, required });
+            }
+        }
+        Err(error) => log::warn!("detecting flash size: {error}"),
+    }
+}
+
+/// Maps an esp_chip_id_t from an image header to the chip it names
+fn chip_from_id(id: u16) -> Option<Chip> {
+    match id {
+        app_image::CHIP_ID_ESP32 => Some(Chip::Esp32),
+        2 => Some(Chip::Esp32s2),
+        5 => Some(Chip::Esp32c3),
+        9 => Some(Chip::Esp32s3),
+        12 => Some(Chip::Esp32c2),
+        13 => Some(Chip::Esp32c6),
+        16 => Some(Chip::Esp32h2),
+        _ => None,
+    }
+}
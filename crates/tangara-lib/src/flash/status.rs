@@ -0,0 +1,257 @@
+//! Progress events sent while a flash runs.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use futures::SinkExt;
+use futures::channel::mpsc;
+
+use crate::firmware::Image;
+
+use super::preflight::PreflightReport;
+
+/// What the flash is doing right now. Connecting and resetting apply to
+/// the whole session; the rest to the current image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlashPhase {
+    Connecting,
+    Erasing,
+    Writing,
+    Verifying,
+    Resetting,
+}
+
+impl fmt::Display for FlashPhase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            FlashPhase::Connecting => "connecting",
+            FlashPhase::Erasing => "erasing",
+            FlashPhase::Writing => "writing",
+            FlashPhase::Verifying => "verifying",
+            FlashPhase::Resetting => "resetting",
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum FlashStatus {
+    /// Flashing has started, with this many images and bytes in total
+    StartingFlash { images: usize, total_bytes: u64 },
+    /// Moving on to the image at `index` (counting from 0) of `count`
+    Image { index: usize, count: usize, name: String, size: u64 },
+    Phase(FlashPhase),
+    /// Connected to the device, flashing at this baud rate
+    Connected { baud: u32 },
+    /// What the preflight checks found out about the device. If there are
+    /// problems, the flash stops here unless it was forced
+    Preflight(PreflightReport),
+    Progress(FlashProgress),
+    /// An image already on the device matches, so wasn't written
+    Skipped(String),
+    /// Writing an image failed, trying again after reconnecting
+    Retrying { image: String, attempt: u32 },
+    /// Every image is on the device
+    Finished(FlashSummary),
+}
+
+#[derive(Clone, Debug)]
+pub struct FlashProgress {
+    /// Bytes of the current image written, out of its size
+    pub image_written: u64,
+    pub image_size: u64,
+    /// Bytes across all images, counting skipped images as written
+    pub written: u64,
+    pub total: u64,
+    /// Bytes per second actually sent to the device so far
+    pub throughput: Option<f64>,
+    pub eta: Option<Duration>,
+}
+
+impl FlashProgress {
+    /// Overall progress from 0.0 to 1.0
+    pub fn fraction(&self) -> f64 {
+        match self.total {
+            0 => 0.0,
+            total => self.written as f64 / total as f64,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FlashSummary {
+    pub images: Vec<ImageSummary>,
+    pub elapsed: Duration,
+    /// Bytes actually sent to the device, which leaves out skipped images
+    pub bytes_written: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct ImageSummary {
+    pub name: String,
+    pub size: u64,
+    pub outcome: ImageOutcome,
+    pub elapsed: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageOutcome {
+    Written,
+    /// Already on the device, either found by a differential flash or
+    /// recorded in the journal of an interrupted one
+    Skipped,
+}
+
+/// Keeps track of where a flash is up to, and turns that into
+/// [`FlashStatus`] events.
+pub(super) struct Reporter {
+    sender: mpsc::Sender<FlashStatus>,
+    started: Instant,
+    total: u64,
+    /// Bytes of images already dealt with, written or skipped
+    done: u64,
+    /// Bytes actually sent to the device
+    sent: u64,
+    phase: Option<FlashPhase>,
+    image: Option<CurrentImage>,
+    summary: Vec<ImageSummary>,
+}
+
+struct CurrentImage {
+    name: String,
+    size: u64,
+    /// Where in the image writing has got to
+    written: u64,
+    /// Where in the image the current write started. Differential writes
+    /// skip over unchanged sectors, so this can jump ahead
+    write_start: u64,
+    started: Instant,
+}
+
+impl Reporter {
+    pub fn start(sender: mpsc::Sender<FlashStatus>, images: &[&Image]) -> Self {
+        let total = images.iter().map(|image| u64::from(image.size)).sum();
+
+        let mut reporter = Reporter {
+            sender,
+            started: Instant::now(),
+            total,
+            done: 0,
+            sent: 0,
+            phase: None,
+            image: None,
+            summary: Vec::new(),
+        };
+
+        reporter.send(FlashStatus::StartingFlash { images: images.len(), total_bytes: total });
+        reporter
+    }
+
+    pub fn phase(&mut self, phase: FlashPhase) {
+        if self.phase != Some(phase) {
+            self.phase = Some(phase);
+            self.send(FlashStatus::Phase(phase));
+        }
+    }
+
+    pub fn connected(&mut self, baud: u32) {
+        self.send(FlashStatus::Connected { baud });
+    }
+
+    pub fn preflight(&mut self, report: PreflightReport) {
+        self.send(FlashStatus::Preflight(report));
+    }
+
+    pub fn start_image(&mut self, index: usize, count: usize, image: &Image) {
+        let size = u64::from(image.size);
+
+        self.image = Some(CurrentImage {
+            name: image.name.clone(),
+            size,
+            written: 0,
+            write_start: 0,
+            started: Instant::now(),
+        });
+
+        self.send(FlashStatus::Image { index, count, name: image.name.clone(), size });
+    }
+
+    /// Starts writing a piece of the current image, at `offset` into it.
+    pub fn start_write(&mut self, offset: u64) {
+        let Some(image) = &mut self.image else { return };
+
+        image.write_start = offset;
+        image.written = offset;
+        self.progress(offset);
+    }
+
+    /// Reports how far through the current image writing has got.
+    pub fn progress(&mut self, image_written: u64) {
+        let Some(image) = &mut self.image else { return };
+
+        // only count what was sent since the current write started, not
+        // the sectors it skipped to get there
+        let previous = image.written.max(image.write_start);
+        self.sent += image_written.saturating_sub(previous);
+        image.written = image_written;
+
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let throughput = (self.sent > 0 && elapsed > 0.0).then(|| self.sent as f64 / elapsed);
+
+        let written = self.done + image_written;
+        let eta = throughput.map(|throughput| {
+            Duration::from_secs_f64(self.total.saturating_sub(written) as f64 / throughput)
+        });
+
+        let status = FlashStatus::Progress(FlashProgress {
+            image_written,
+            image_size: image.size,
+            written,
+            total: self.total,
+            throughput,
+            eta,
+        });
+
+        // use try_send here because it's ok if we drop a message
+        let _ = self.sender.try_send(status);
+    }
+
+    pub fn retrying(&mut self, attempt: u32) {
+        let Some(image) = &self.image else { return };
+        let status = FlashStatus::Retrying { image: image.name.clone(), attempt };
+        self.send(status);
+    }
+
+    pub fn finish_image(&mut self, outcome: ImageOutcome) {
+        let Some(image) = self.image.take() else { return };
+
+        if outcome == ImageOutcome::Skipped {
+            self.send(FlashStatus::Skipped(image.name.clone()));
+        }
+
+        self.done += image.size;
+
+        self.summary.push(ImageSummary {
+            name: image.name,
+            size: image.size,
+            outcome,
+            elapsed: image.started.elapsed(),
+        });
+    }
+
+    pub fn finish(mut self) {
+        let summary = FlashSummary {
+            images: std::mem::take(&mut self.summary),
+            elapsed: self.started.elapsed(),
+            bytes_written: self.sent,
+        };
+
+        self.send(FlashStatus::Finished(summary));
+    }
+
+    /// Sends an event that mustn't be dropped, waiting for room in the
+    /// channel if need be. This runs on the flash thread, so blocking is
+    /// fine.
+    fn send(&mut self, status: FlashStatus) {
+        let _ = futures::executor::block_on(self.sender.send(status));
+    }
+}
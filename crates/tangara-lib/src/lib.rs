@@ -1,4 +1,6 @@
+pub mod cache;
 pub mod firmware;
 pub mod flash;
+pub mod release;
 pub mod device;
 pub mod serial;
@@ -0,0 +1,468 @@
+//! Firmware releases published on a Forgejo (Codeberg) or Gitea
+//! repository, and downloading them.
+//!
+//! This uses reqwest, so must be run on a tokio runtime.
+
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use futures::SinkExt;
+use futures::channel::mpsc;
+use semver::Version;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::cache::{Cache, CacheError};
+use crate::firmware;
+
+/// The official tangara-fw repository. Anything serving the same API can
+/// be used instead, eg. a fork or a local mirror.
+pub const DEFAULT_SOURCE: &str = "https://codeberg.org/api/v1/repos/cool-tech-zone/tangara-fw";
+
+/// Environment variable that overrides [`DEFAULT_SOURCE`]
+pub const SOURCE_ENV: &str = "TANGARA_RELEASE_SOURCE";
+
+const PAGE_LIMIT: usize = 50;
+const MAX_PAGES: usize = 20;
+
+/// Where to look for releases: the repository named by [`SOURCE_ENV`] if
+/// it's set, otherwise the official one.
+pub fn default_source() -> String {
+    source_or_default(std::env::var(SOURCE_ENV).ok())
+}
+
+/// `source` if it's given and not empty, otherwise the official repository
+fn source_or_default(source: Option<String>) -> String {
+    source
+        .filter(|source| !source.is_empty())
+        .unwrap_or_else(|| DEFAULT_SOURCE.to_owned())
+}
+
+#[derive(Error, Debug)]
+pub enum ReleaseError {
+    #[error("http error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("no firmware releases found")]
+    NoRelease,
+    #[error("no firmware release with version {0}")]
+    NotFound(Version),
+    #[error("release {0} has no firmware archive")]
+    MissingAsset(Version),
+}
+
+#[derive(Error, Debug)]
+pub enum DownloadError {
+    #[error(transparent)]
+    Release(#[from] ReleaseError),
+    #[error("http error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("saving firmware archive: {0}")]
+    Save(#[from] io::Error),
+    #[error("no checksum for {0} in release checksums")]
+    NoChecksum(String),
+    #[error("downloaded archive has SHA-256 {0}, but the release says {1}")]
+    BadChecksum(String, String),
+    #[error(transparent)]
+    Cache(#[from] CacheError),
+}
+
+#[derive(Clone, Debug)]
+pub enum DownloadStatus {
+    Progress { downloaded: u64, total: Option<u64> },
+    Verifying,
+    /// The release publishes no checksums, so the download couldn't be
+    /// checked
+    Unverified,
+}
+
+#[derive(Clone, Debug)]
+pub struct Asset {
+    pub name: String,
+    pub url: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Release {
+    pub version: Version,
+    pub name: String,
+    pub prerelease: bool,
+    pub published_at: Option<String>,
+    pub notes: Option<String>,
+    /// The release's firmware archive, if it has one
+    pub archive: Option<Asset>,
+    /// A `SHA256SUMS` file, or a `.sha256` file alongside the archive
+    pub checksums: Option<Asset>,
+}
+
+impl Release {
+    /// Either marked as a prerelease, or with a prerelease version
+    pub fn is_prerelease(&self) -> bool {
+        self.prerelease || !self.version.pre.is_empty()
+    }
+
+    pub fn archive(&self) -> Result<&Asset, ReleaseError> {
+        self.archive.as_ref()
+            .ok_or_else(|| ReleaseError::MissingAsset(self.version.clone()))
+    }
+}
+
+pub struct ReleaseCatalog {
+    /// Newest first
+    releases: Vec<Release>,
+}
+
+impl ReleaseCatalog {
+    /// Fetches every published release from `source`, the API URL of a
+    /// repository.
+    pub async fn fetch(source: &str) -> Result<Self, ReleaseError> {
+        #[derive(Deserialize)]
+        struct ApiRelease {
+            tag_name: String,
+            name: String,
+            #[serde(default)]
+            body: Option<String>,
+            #[serde(default)]
+            draft: bool,
+            #[serde(default)]
+            prerelease: bool,
+            #[serde(default)]
+            published_at: Option<String>,
+            assets: Vec<ApiAsset>,
+        }
+
+        #[derive(Deserialize)]
+        struct ApiAsset {
+            name: String,
+            browser_download_url: String,
+        }
+
+        let source = source.trim_end_matches('/');
+        let client = reqwest::Client::new();
+        let mut releases = Vec::new();
+
+        for page in 1..=MAX_PAGES {
+            let url = format!("{source}/releases?page={page}&limit={PAGE_LIMIT}");
+
+            let page: Vec<ApiRelease> = client.get(&url)
+                .send().await?
+                .error_for_status()?
+                .json().await?;
+
+            let count = page.len();
+
+            for release in page {
+                if release.draft {
+                    continue;
+                }
+
+                let Some(version) = release_version(&release.tag_name)
+                    .or_else(|| release_version(&release.name)) else {
+                    log::debug!("skipping release with unrecognised version: {}", release.tag_name);
+                    continue;
+                };
+
+                let assets = release.assets.into_iter()
+                    .map(|asset| Asset { name: asset.name, url: asset.browser_download_url })
+                    .collect::<Vec<_>>();
+
+                let archive = assets.iter()
+                    .find(|asset| asset.name.ends_with(".tra"))
+                    .cloned();
+
+                let checksums = assets.iter()
+                    .find(|asset| {
+                        asset.name == "SHA256SUMS" || archive.as_ref()
+                            .is_some_and(|archive| asset.name == format!("{}.sha256", archive.name))
+                    })
+                    .cloned();
+
+                releases.push(Release {
+                    version,
+                    name: release.name,
+                    prerelease: release.prerelease,
+                    published_at: release.published_at,
+                    notes: release.body.filter(|body| !body.trim().is_empty()),
+                    archive,
+                    checksums,
+                });
+            }
+
+            if count < PAGE_LIMIT {
+                break;
+            }
+        }
+
+        releases.sort_by(|a, b| b.version.cmp(&a.version));
+
+        Ok(ReleaseCatalog { releases })
+    }
+
+    /// Releases newest first, leaving out prereleases unless asked for.
+    pub fn releases(&self, include_prerelease: bool) -> impl Iterator<Item = &Release> {
+        self.releases.iter()
+            .filter(move |release| include_prerelease || !release.is_prerelease())
+    }
+
+    pub fn latest(&self, include_prerelease: bool) -> Result<&Release, ReleaseError> {
+        self.releases(include_prerelease)
+            .find(|release| release.archive.is_some())
+            .ok_or(ReleaseError::NoRelease)
+    }
+
+    /// Finds a specific version, whether or not it's a prerelease.
+    pub fn find(&self, version: &Version) -> Result<&Release, ReleaseError> {
+        self.releases.iter()
+            .find(|release| release.version == *version)
+            .ok_or_else(|| ReleaseError::NotFound(version.clone()))
+    }
+}
+
+/// Downloads a release's firmware archive to `out`, reporting progress as
+/// it goes. If the release publishes checksums, the download is checked
+/// against them.
+pub async fn download(
+    release: &Release,
+    out: &mut impl Write,
+    mut progress: mpsc::Sender<DownloadStatus>,
+) -> Result<(), DownloadError> {
+    let archive = release.archive()?;
+
+    let mut response = reqwest::get(&archive.url).await?.error_for_status()?;
+    let total = response.content_length();
+    let mut downloaded = 0;
+    let mut hasher = Sha256::new();
+
+    while let Some(chunk) = response.chunk().await? {
+        out.write_all(&chunk)?;
+        hasher.update(&chunk);
+        downloaded += chunk.len() as u64;
+
+        // use try_send here because it's ok if we drop a message
+        let _ = progress.try_send(DownloadStatus::Progress { downloaded, total });
+    }
+
+    out.flush()?;
+
+    let Some(checksums) = &release.checksums else {
+        log::warn!("release {} publishes no checksums, not verifying download", release.version);
+        // not try_send, this one shouldn't be dropped
+        let _ = progress.send(DownloadStatus::Unverified).await;
+        return Ok(());
+    };
+
+    let _ = progress.try_send(DownloadStatus::Verifying);
+
+    let asset = &checksums.name;
+    let checksums = reqwest::get(&checksums.url).await?
+        .error_for_status()?
+        .text().await?;
+
+    let expected = find_checksum(&checksums, asset, &archive.name)
+        .ok_or_else(|| DownloadError::NoChecksum(archive.name.clone()))?;
+
+    let actual = firmware::hex(&hasher.finalize());
+
+    if actual != expected {
+        return Err(DownloadError::BadChecksum(actual, expected));
+    }
+
+    Ok(())
+}
+
+/// Downloads a release's firmware archive into the cache, returning the
+/// path to the cached archive.
+pub async fn download_to_cache(
+    release: &Release,
+    cache: &mut Cache,
+    progress: mpsc::Sender<DownloadStatus>,
+) -> Result<PathBuf, DownloadError> {
+    let archive = release.archive()?;
+    // the asset name comes from the server, so make sure it stays put
+    let partial = cache.file_path(&format!("{}.download", archive.name))?;
+
+    let mut file = std::fs::File::create(&partial)?;
+    let result = download(release, &mut file, progress).await;
+    drop(file);
+
+    let result = match result {
+        Ok(()) => cache.insert(&partial, Some(&archive.url))
+            .map(|entry| cache.path(&entry))
+            .map_err(DownloadError::from),
+        Err(error) => Err(error),
+    };
+
+    let _ = std::fs::remove_file(&partial);
+    result
+}
+
+/// Finds a file's hash in `sha256sum` output. A bare hash is only taken
+/// from a `.sha256` file dedicated to that one file, as `asset` says.
+fn find_checksum(checksums: &str, asset: &str, name: &str) -> Option<String> {
+    let dedicated = asset == format!("{name}.sha256");
+
+    checksums.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let hash = fields.next()?;
+
+        if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        // sha256sum marks binary mode with a '*' before the file name
+        let matches = match fields.next().map(|file| file.trim_start_matches('*')) {
+            Some(file) => file == name,
+            None => dedicated,
+        };
+
+        matches.then(|| hash.to_ascii_lowercase())
+    })
+}
+
+pub fn release_version(name: &str) -> Option<Version> {
+    Version::parse(name.trim().trim_start_matches('v')).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    /// A stand-in for the releases API, serving `pages` in order. Returns
+    /// its URL, and the paths it was asked for.
+    fn serve(pages: Vec<Value>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/v1/repos/test/tangara-fw", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        std::thread::spawn({
+            let requests = requests.clone();
+            move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let mut reader = BufReader::new(&stream);
+
+                    let mut request_line = String::new();
+                    reader.read_line(&mut request_line).unwrap();
+                    let path = request_line.split_whitespace().nth(1).unwrap_or_default().to_owned();
+
+                    // skip the headers
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).unwrap() > 2 {
+                        line.clear();
+                    }
+
+                    let page = path.split_once("page=")
+                        .and_then(|(_, rest)| rest.split('&').next()?.parse::<usize>().ok())
+                        .unwrap_or(1);
+
+                    let body = pages.get(page - 1).cloned().unwrap_or(json!([])).to_string();
+                    requests.lock().unwrap().push(path);
+
+                    write!(stream,
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                        body.len()).unwrap();
+                }
+            }
+        });
+
+        (url, requests)
+    }
+
+    fn release(tag: &str, prerelease: bool) -> Value {
+        json!({
+            "tag_name": tag,
+            "name": tag,
+            "body": "",
+            "draft": false,
+            "prerelease": prerelease,
+            "published_at": "2024-01-01T00:00:00Z",
+            "assets": [{
+                "name": format!("tangarafw-{tag}.tra"),
+                "browser_download_url": format!("https://example.com/tangarafw-{tag}.tra"),
+            }],
+        })
+    }
+
+    #[tokio::test]
+    async fn fetches_every_page() {
+        let first = (0..PAGE_LIMIT)
+            .map(|patch| release(&format!("v1.0.{patch}"), false))
+            .collect::<Vec<_>>();
+        let second = vec![release("v1.1.0", false)];
+
+        let (url, requests) = serve(vec![json!(first), json!(second)]);
+        let catalog = ReleaseCatalog::fetch(&url).await.unwrap();
+
+        assert_eq!(catalog.releases(false).count(), PAGE_LIMIT + 1);
+        assert_eq!(catalog.latest(false).unwrap().version, Version::new(1, 1, 0));
+
+        // a short page is the last one
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn filters_prereleases() {
+        let (url, _) = serve(vec![json!([
+            release("v1.2.0-beta.1", false),
+            release("v1.1.1", true),
+            release("v1.1.0", false),
+        ])]);
+
+        let catalog = ReleaseCatalog::fetch(&url).await.unwrap();
+
+        assert_eq!(catalog.releases(false).count(), 1);
+        assert_eq!(catalog.latest(false).unwrap().version, Version::new(1, 1, 0));
+        assert_eq!(catalog.latest(true).unwrap().version, Version::parse("1.2.0-beta.1").unwrap());
+    }
+
+    #[tokio::test]
+    async fn finds_specific_version() {
+        let (url, _) = serve(vec![json!([
+            release("v1.1.1", true),
+            release("v1.1.0", false),
+        ])]);
+
+        let catalog = ReleaseCatalog::fetch(&url).await.unwrap();
+
+        let release = catalog.find(&Version::new(1, 1, 1)).unwrap();
+        assert!(release.is_prerelease());
+        assert_eq!(release.archive().unwrap().name, "tangarafw-v1.1.1.tra");
+
+        assert!(matches!(
+            catalog.find(&Version::new(2, 0, 0)),
+            Err(ReleaseError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn checksum_must_name_the_file() {
+        let hash = "ab".repeat(32);
+        let sums = format!("{hash}  other.tra\n{}  *tangarafw-v1.0.0.tra\n", "cd".repeat(32));
+
+        assert_eq!(find_checksum(&sums, "SHA256SUMS", "tangarafw-v1.0.0.tra"), Some("cd".repeat(32)));
+        assert_eq!(find_checksum(&hash, "SHA256SUMS", "tangarafw-v1.0.0.tra"), None);
+        assert_eq!(find_checksum(&hash, "tangarafw-v1.0.0.tra.sha256", "tangarafw-v1.0.0.tra"), Some(hash.clone()));
+        assert_eq!(find_checksum("not-a-hash", "tangarafw-v1.0.0.tra.sha256", "tangarafw-v1.0.0.tra"), None);
+    }
+
+    #[tokio::test]
+    async fn source_override() {
+        let (url, requests) = serve(vec![json!([release("v1.0.0", false)])]);
+
+        assert_eq!(source_or_default(None), DEFAULT_SOURCE);
+        assert_eq!(source_or_default(Some(String::new())), DEFAULT_SOURCE);
+
+        let source = source_or_default(Some(url.clone()));
+        assert_eq!(source, url);
+
+        let catalog = ReleaseCatalog::fetch(&source).await.unwrap();
+        assert_eq!(catalog.latest(false).unwrap().version, Version::new(1, 0, 0));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+}
@@ -1,37 +1,28 @@
-use std::time::Duration;
+use futures::{pin_mut, Stream, StreamExt};
+use tangara_lib::device::{self, ConnectionParams, Tangara};
+use tangara_lib::device::hotplug::{self, HotplugEvent};
 
-use futures::Stream;
-use gtk::glib;
-use tangara_lib::device::{ConnectionParams, FindTangaraError, Tangara};
-
-const POLL_DURATION: Duration = Duration::from_secs(1);
-
-pub fn watch_port() -> impl Stream<Item = Option<ConnectionParams>> {
+pub fn watch_port() -> impl Stream<Item = Vec<ConnectionParams>> {
     async_stream::stream! {
-        let mut current = find_device();
+        let mut current = Tangara::find_with_bridges();
         yield current.clone();
 
-        loop {
-            // TODO - see if we can subscribe to hardware events or something?
-            glib::timeout_future(POLL_DURATION).await;
+        let events = hotplug::watch(current.clone());
+        pin_mut!(events);
 
-            let params = find_device();
-
-            if params != current {
-                current = params;
-                yield current.clone();
+        while let Some(event) = events.next().await {
+            match event {
+                HotplugEvent::Attach(params) => {
+                    current.push(params);
+                }
+                HotplugEvent::Detach(params) => {
+                    current.retain(|device| device != &params);
+                }
             }
-        }
-    }
-}
 
-fn find_device() -> Option<ConnectionParams> {
-    match Tangara::find() {
-        Ok(params) => Some(params),
-        Err(FindTangaraError::NoTangara) => None,
-        Err(err) => {
-            log::warn!("find_device: {err}");
-            None
+            device::sort_devices(&mut current);
+
+            yield current.clone();
         }
     }
 }
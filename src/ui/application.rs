@@ -4,6 +4,7 @@ use std::time::Duration;
 use futures::pin_mut;
 use futures::StreamExt;
 use tangara_lib::device::{ConnectionParams, Tangara};
+use tangara_lib::flash;
 
 use crate::device::watch_port;
 use crate::ui;
@@ -50,17 +51,54 @@ pub fn run_application(app: &adw::Application) -> adw::ApplicationWindow {
     window
 }
 
+enum WatchEvent {
+    Devices(Vec<ConnectionParams>),
+    Selected(ConnectionParams),
+}
+
 async fn watch_loop(view: Rc<MainView>) {
-    let watch = watch_port();
-    pin_mut!(watch);
+    let (select_tx, select_rx) = async_channel::unbounded();
+
+    view.connect_device_selected(move |params| {
+        let _ = select_tx.try_send(params);
+    });
+
+    let events = futures::stream::select(
+        watch_port().map(WatchEvent::Devices),
+        select_rx.map(WatchEvent::Selected),
+    );
+    pin_mut!(events);
 
     view.show_welcome();
 
+    let mut current: Option<ConnectionParams> = None;
+
     loop {
-        let Some(item) = watch.next().await else { break };
+        let Some(event) = events.next().await else { break };
+
+        let selected = match event {
+            WatchEvent::Devices(devices) => {
+                // stay with the current device for as long as it's plugged
+                // in. usb serial bridges could be anything, so they're
+                // never picked automatically
+                let selected = current.clone()
+                    .filter(|params| devices.contains(params))
+                    .or_else(|| devices.iter().find(|params| !params.is_bootloader()).cloned());
+
+                view.set_devices(&devices, selected.as_ref());
+                selected
+            }
+            WatchEvent::Selected(params) => Some(params),
+        };
+
+        if selected == current {
+            continue;
+        }
 
-        if let Some(params) = item {
-            found_device(view.clone(), params).await;
+        current = selected;
+
+        if let Some(params) = &current {
+            found_device(view.clone(), params.clone()).await;
         } else {
             view.show_welcome();
         }
@@ -68,6 +106,12 @@ async fn watch_loop(view: Rc<MainView>) {
 }
 
 async fn found_device(view: Rc<MainView>, params: ConnectionParams) {
+    // no console to connect to in the bootloader, all we can do is flash
+    if params.is_bootloader() {
+        probe_bootloader(view, params).await;
+        return;
+    }
+
     view.show_connecting(&params);
 
     'retry_connection: loop {
@@ -110,6 +154,35 @@ async fn found_device(view: Rc<MainView>, params: ConnectionParams) {
     }
 }
 
+/// Makes sure there's an ESP32 on the other end of a usb serial bridge
+/// before offering to flash it.
+async fn probe_bootloader(view: Rc<MainView>, params: ConnectionParams) {
+    view.show_connecting(&params);
+
+    loop {
+        let error = match flash::probe_bootloader(&params).await {
+            Ok(_) => {
+                view.show_rescue(&params);
+                return;
+            }
+            Err(error) => error,
+        };
+
+        let choice = SendOnce::with(|choice| {
+            view.device_error(&params, &error, choice)
+        }).await;
+
+        match choice {
+            // probing resets the device anyway
+            Some(DeviceErrorChoice::Retry | DeviceErrorChoice::Reboot) => continue,
+            Some(DeviceErrorChoice::Reinstall) => view.show_rescue(&params),
+            None => {}
+        }
+
+        return;
+    }
+}
+
 async fn try_connect(view: Rc<MainView>, params: &ConnectionParams) -> Option<DeviceErrorChoice> {
     match Tangara::open(&params).await {
         Ok(tangara) => {
@@ -0,0 +1,84 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use derive_more::Deref;
+use gtk::prelude::WidgetExt;
+
+use tangara_lib::device::ConnectionParams;
+
+/// Dropdown for choosing between devices when more than one Tangara is
+/// plugged in. Hidden when there's only one to choose from.
+#[derive(Deref)]
+pub struct DevicePicker {
+    #[deref]
+    dropdown: gtk::DropDown,
+    devices: Rc<RefCell<Vec<ConnectionParams>>>,
+    updating: Rc<Cell<bool>>,
+}
+
+impl DevicePicker {
+    pub fn new() -> Self {
+        let dropdown = gtk::DropDown::builder()
+            .margin_start(6)
+            .margin_end(6)
+            .margin_top(6)
+            .visible(false)
+            .build();
+
+        DevicePicker {
+            dropdown,
+            devices: Default::default(),
+            updating: Default::default(),
+        }
+    }
+
+    pub fn set_devices(&self, devices: &[ConnectionParams], selected: Option<&ConnectionParams>) {
+        let labels = devices.iter().map(device_label).collect::<Vec<_>>();
+        let labels = labels.iter().map(String::as_str).collect::<Vec<_>>();
+
+        // nothing selected when there are only usb serial bridges, so that
+        // picking one still counts as a change
+        let index = selected
+            .and_then(|selected| devices.iter().position(|params| params == selected))
+            .map(|index| index as u32)
+            .unwrap_or(gtk::INVALID_LIST_POSITION);
+
+        // don't report selection changes caused by replacing the model
+        self.updating.set(true);
+        *self.devices.borrow_mut() = devices.to_vec();
+        self.dropdown.set_model(Some(&gtk::StringList::new(&labels)));
+        self.dropdown.set_selected(index);
+        self.updating.set(false);
+
+        // bridges are never picked automatically, so there must be a way
+        // to pick one
+        let has_bridge = devices.iter().any(ConnectionParams::is_bootloader);
+        self.dropdown.set_visible(devices.len() > 1 || has_bridge);
+    }
+
+    pub fn connect_selected(&self, func: impl Fn(ConnectionParams) + 'static) {
+        let devices = self.devices.clone();
+        let updating = self.updating.clone();
+
+        self.dropdown.connect_selected_notify(move |dropdown| {
+            if updating.get() {
+                return;
+            }
+
+            let Ok(index) = usize::try_from(dropdown.selected()) else { return };
+            let Some(params) = devices.borrow().get(index).cloned() else { return };
+            func(params);
+        });
+    }
+}
+
+fn device_label(params: &ConnectionParams) -> String {
+    if params.is_bootloader() {
+        return format!("USB serial bridge ({})", params.serial.port_name);
+    }
+
+    match params.serial_number() {
+        Some(serial) => format!("Tangara {serial} ({})", params.serial.port_name),
+        None => format!("Tangara ({})", params.serial.port_name),
+    }
+}
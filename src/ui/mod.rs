@@ -1,4 +1,5 @@
 mod application;
+mod device_picker;
 mod spinner;
 mod label_row;
 mod lua;
@@ -11,6 +11,7 @@ use tangara_lib::device::{ConnectionParams, Tangara};
 
 use crate::ui;
 use crate::ui::application::DeviceContext;
+use crate::ui::device_picker::DevicePicker;
 use crate::ui::util::NavPageBuilder;
 
 use super::application::DeviceErrorChoice;
@@ -48,6 +49,14 @@ impl MainView {
         self.split.set_content(Some(page));
     }
 
+    pub fn set_devices(&self, devices: &[ConnectionParams], selected: Option<&ConnectionParams>) {
+        self.sidebar.device_picker.set_devices(devices, selected);
+    }
+
+    pub fn connect_device_selected(&self, func: impl Fn(ConnectionParams) + 'static) {
+        self.sidebar.device_picker.connect_selected(func);
+    }
+
     pub fn show_welcome(&self) {
         self.show_page_without_sidebar(&ui::welcome::page());
     }
@@ -148,15 +157,24 @@ impl MainView {
 pub struct Sidebar {
     #[deref]
     sidebar: adw::NavigationPage,
+    device_picker: DevicePicker,
     device_nav: adw::Bin,
 }
 
 impl Sidebar {
     pub fn new() -> Self {
+        let device_picker = DevicePicker::new();
         let device_nav = adw::Bin::new();
 
+        let content = gtk::Box::builder()
+            .orientation(gtk::Orientation::Vertical)
+            .build();
+
+        content.append(&*device_picker);
+        content.append(&device_nav);
+
         let view = adw::ToolbarView::builder()
-            .content(&device_nav)
+            .content(&content)
             .build();
 
         view.add_top_bar(
@@ -170,6 +188,7 @@ impl Sidebar {
 
         Sidebar {
             sidebar,
+            device_picker,
             device_nav,
         }
     }
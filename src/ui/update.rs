@@ -1,9 +1,12 @@
+use std::cell::{Cell, RefCell};
+use std::path::Path;
 use std::rc::Rc;
 use std::sync::Arc;
+use std::time::Duration;
 
-use adw::prelude::{ActionRowExt, PreferencesGroupExt, PreferencesPageExt};
+use adw::prelude::{ActionRowExt, ComboRowExt, PreferencesGroupExt, PreferencesPageExt, PreferencesRowExt};
 use futures::StreamExt;
-use futures::channel::mpsc::Receiver;
+use futures::channel::mpsc::{self, Receiver};
 use glib::object::Cast;
 use glib::types::StaticType;
 use glib::WeakRef;
@@ -11,14 +14,17 @@ use gtk::{Align, FileDialog, FileFilter, Orientation};
 use gtk::gio::{Cancellable, File};
 use gtk::prelude::{BoxExt, ButtonExt, FileExt, WidgetExt};
 
+use tangara_lib::cache::Cache;
 use tangara_lib::device::ConnectionParams;
 use tangara_lib::firmware::Firmware;
-use tangara_lib::flash::{self, FlashError, FlashStatus};
+use tangara_lib::flash::{self, FlashError, FlashOptions, FlashPhase, FlashStatus};
+use tangara_lib::flash::journal::Journal;
+use tangara_lib::release::{self, DownloadStatus, Release, ReleaseCatalog};
 
 use crate::ui::application::DeviceContext;
 use crate::ui::label_row::LabelRow;
 use crate::ui::util::NavPageBuilder;
-use crate::util::weak;
+use crate::util::{spawn_tokio, weak};
 
 pub fn flow(device: DeviceContext) -> adw::NavigationPage {
     let nav = adw::NavigationView::new();
@@ -26,6 +32,7 @@ pub fn flow(device: DeviceContext) -> adw::NavigationPage {
     nav.add(&select_firmware_page("Update Firmware", UpdateContext {
         target: Rc::new(UpdateTarget::Device(device)),
         nav: weak(&nav),
+        baud: Rc::new(Cell::new(flash::DEFAULT_BAUD_RATE)),
     }));
 
     adw::NavigationPage::builder()
@@ -39,6 +46,7 @@ pub fn rescue_flow(params: ConnectionParams) -> adw::NavigationPage {
     nav.add(&select_firmware_page("Reinstall Firmware", UpdateContext {
         target: Rc::new(UpdateTarget::Params(params)),
         nav: weak(&nav),
+        baud: Rc::new(Cell::new(flash::DEFAULT_BAUD_RATE)),
     }));
 
     adw::NavigationPage::builder()
@@ -50,6 +58,17 @@ pub fn rescue_flow(params: ConnectionParams) -> adw::NavigationPage {
 struct UpdateContext {
     target: Rc<UpdateTarget>,
     nav: WeakRef<adw::NavigationView>,
+    /// Chosen under advanced options on the review page
+    baud: Rc<Cell<u32>>,
+}
+
+impl UpdateContext {
+    fn flash_options(&self) -> FlashOptions {
+        FlashOptions {
+            baud: self.baud.get(),
+            ..FlashOptions::default()
+        }
+    }
 }
 
 enum UpdateTarget {
@@ -57,12 +76,26 @@ enum UpdateTarget {
     Params(ConnectionParams),
 }
 
+impl UpdateTarget {
+    fn params(&self) -> &ConnectionParams {
+        match self {
+            UpdateTarget::Device(device) => device.tangara.params(),
+            UpdateTarget::Params(params) => params,
+        }
+    }
+}
+
 fn select_firmware_page(title: &str, ctx: UpdateContext) -> adw::NavigationPage {
     let page = adw::PreferencesPage::builder()
         .title(title)
         .build();
 
-    page.add(&select_group(ctx));
+    page.add(&select_group(ctx.clone()));
+    page.add(&online_group(ctx.clone()));
+
+    if let Some(group) = cached_group(ctx) {
+        page.add(&group);
+    }
 
     NavPageBuilder::clamped(&page)
         .title(page.title().as_str())
@@ -99,6 +132,7 @@ fn select_group(ctx: UpdateContext) -> adw::PreferencesGroup {
             .build()
             .open(window.as_ref(), Cancellable::NONE, {
                 let ctx = ctx.clone();
+                let row = widget.clone();
                 move |result| {
                     let Some(nav) = ctx.nav.upgrade() else { return };
 
@@ -117,24 +151,234 @@ fn select_group(ctx: UpdateContext) -> adw::PreferencesGroup {
                         return;
                     };
 
-                    match Firmware::open(&path) {
-                        Ok(firmware) => {
-                            let firmware = Arc::new(firmware);
-                            nav.push(&review_firmware_page(ctx.clone(), firmware));
+                    open_firmware(&ctx, &nav, &path, &row);
+                }
+            });
+    });
+
+    group.add(&select_firmware);
+
+    group
+}
+
+/// Checks for the latest release online, and downloads it into the cache
+/// to flash.
+fn online_group(ctx: UpdateContext) -> adw::PreferencesGroup {
+    let group = adw::PreferencesGroup::builder()
+        .title("Online")
+        .build();
+
+    let check_row = adw::ActionRow::builder()
+        .title("Check for updates")
+        .activatable(true)
+        .build();
+
+    let download_row = adw::ActionRow::builder()
+        .title("Download latest")
+        .activatable(true)
+        .visible(false)
+        .build();
+
+    let progress_bar = gtk::ProgressBar::builder()
+        .valign(Align::Center)
+        .visible(false)
+        .build();
+
+    download_row.add_suffix(&progress_bar);
+
+    let latest = Rc::new(RefCell::new(None::<Release>));
+
+    check_row.connect_activated({
+        let ctx = ctx.clone();
+        let latest = latest.clone();
+        let download_row = download_row.clone();
+        move |row| {
+            row.set_sensitive(false);
+            row.set_subtitle("Checking...");
+
+            let result = spawn_tokio(|| async {
+                let catalog = ReleaseCatalog::fetch(&release::default_source()).await?;
+                catalog.latest(false).cloned()
+            });
+
+            let ctx = ctx.clone();
+            let row = row.clone();
+            let latest = latest.clone();
+            let download_row = download_row.clone();
+
+            glib::spawn_future_local(async move {
+                let result = result.await;
+
+                // compare with what the device is running, if we can ask it
+                let installed = match &*ctx.target {
+                    UpdateTarget::Device(device) => {
+                        device.tangara.connection().firmware_version().await.ok()
+                            .and_then(|version| release::release_version(&version))
+                    }
+                    UpdateTarget::Params(_) => None,
+                };
+
+                row.set_sensitive(true);
+
+                match result {
+                    Ok(Ok(release)) if installed.as_ref().is_some_and(|installed| *installed >= release.version) => {
+                        row.set_subtitle(&format!("Tangara is up to date, latest release is {}", release.version));
+                        download_row.set_visible(false);
+                        *latest.borrow_mut() = None;
+                    }
+                    Ok(Ok(release)) => {
+                        row.set_subtitle(&format!("Latest release is {}", release.version));
+                        download_row.set_title(&format!("Download {}", release.version));
+                        download_row.set_visible(true);
+                        *latest.borrow_mut() = Some(release);
+                    }
+                    Ok(Err(error)) => row.set_subtitle(&format!("{error}")),
+                    Err(_) => row.set_subtitle("Unknown error"),
+                }
+            });
+        }
+    });
+
+    download_row.connect_activated(move |row| {
+        let Some(release) = latest.borrow().clone() else { return };
+
+        row.set_sensitive(false);
+        row.set_subtitle("");
+        progress_bar.set_fraction(0.0);
+        progress_bar.set_visible(true);
+
+        let (progress_tx, mut progress) = mpsc::channel(32);
+
+        let result = spawn_tokio(move || async move {
+            let mut cache = Cache::open()?;
+
+            // no need to download it again if we already have it
+            if let Ok(path) = cache.checked_path(&release.version.to_string()) {
+                return Ok(path);
+            }
+
+            release::download_to_cache(&release, &mut cache, progress_tx).await
+        });
+
+        glib::spawn_future_local({
+            let progress_bar = progress_bar.clone();
+            let row = row.clone();
+            async move {
+                while let Some(status) = progress.next().await {
+                    match status {
+                        DownloadStatus::Progress { downloaded, total: Some(total) } if total != 0 => {
+                            progress_bar.set_fraction(downloaded as f64 / total as f64);
                         }
-                        Err(error) => {
-                            eprintln!("read firmware error: {}", error);
+                        DownloadStatus::Progress { .. } => {
+                            progress_bar.pulse();
+                        }
+                        DownloadStatus::Verifying => {
+                            progress_bar.set_fraction(1.0);
+                        }
+                        DownloadStatus::Unverified => {
+                            row.set_subtitle("Release publishes no checksums, download not verified");
                         }
                     }
                 }
-            });
+            }
+        });
+
+        let ctx = ctx.clone();
+        let row = row.clone();
+        let progress_bar = progress_bar.clone();
+
+        glib::spawn_future_local(async move {
+            let result = result.await;
+
+            row.set_sensitive(true);
+            progress_bar.set_visible(false);
+
+            match result {
+                Ok(Ok(path)) => {
+                    let Some(nav) = ctx.nav.upgrade() else { return };
+                    open_firmware(&ctx, &nav, &path, &row);
+                }
+                Ok(Err(error)) => row.set_subtitle(&format!("{error}")),
+                Err(_) => row.set_subtitle("Unknown error"),
+            }
+        });
     });
 
-    group.add(&select_firmware);
+    group.add(&check_row);
+    group.add(&download_row);
 
     group
 }
 
+/// Firmware previously downloaded into the cache, which can be flashed
+/// without going online.
+fn cached_group(ctx: UpdateContext) -> Option<adw::PreferencesGroup> {
+    let cache = match Cache::open() {
+        Ok(cache) => cache,
+        Err(error) => {
+            log::warn!("can't open firmware cache: {error}");
+            return None;
+        }
+    };
+
+    let entries = cache.entries();
+
+    if entries.is_empty() {
+        return None;
+    }
+
+    let group = adw::PreferencesGroup::builder()
+        .title("Downloaded firmware")
+        .build();
+
+    for entry in entries {
+        let row = adw::ActionRow::builder()
+            .title(&entry.version)
+            .activatable(true)
+            .build();
+
+        row.add_suffix(&gtk::Image::builder()
+            .icon_name("go-next-symbolic")
+            .build());
+
+        let version = entry.version.clone();
+
+        row.connect_activated({
+            let ctx = ctx.clone();
+            move |row| {
+                let Some(nav) = ctx.nav.upgrade() else { return };
+
+                // make sure it hasn't changed since it was downloaded
+                match Cache::open().and_then(|cache| cache.checked_path(&version)) {
+                    Ok(path) => {
+                        row.set_subtitle("");
+                        open_firmware(&ctx, &nav, &path, row);
+                    }
+                    Err(error) => row.set_subtitle(&format!("{error}")),
+                }
+            }
+        });
+
+        group.add(&row);
+    }
+
+    Some(group)
+}
+
+/// Opens an archive to review, or shows why it can't be opened on `row`.
+fn open_firmware(ctx: &UpdateContext, nav: &adw::NavigationView, path: &Path, row: &adw::ActionRow) {
+    match Firmware::open(path) {
+        Ok(firmware) => {
+            let firmware = Arc::new(firmware);
+            nav.push(&review_firmware_page(ctx.clone(), firmware));
+        }
+        Err(error) => {
+            log::error!("reading firmware {}: {error}", path.display());
+            row.set_subtitle(&format!("Can't open firmware: {error}"));
+        }
+    }
+}
+
 fn review_firmware_page(ctx: UpdateContext, firmware: Arc<Firmware>) -> adw::NavigationPage {
     let intro_group = adw::PreferencesGroup::new();
 
@@ -150,10 +394,66 @@ fn review_firmware_page(ctx: UpdateContext, firmware: Arc<Firmware>) -> adw::Nav
     details_group.add(&*LabelRow::new("Firmware", &firmware.path().display().to_string()));
     details_group.add(&*LabelRow::new("Version", firmware.version()));
 
+    if let Some(date) = firmware.release_date() {
+        details_group.add(&*LabelRow::new("Release date", date));
+    }
+
+    if let Some(hardware) = firmware.hardware_revision() {
+        details_group.add(&*LabelRow::new("Hardware revision", hardware));
+    }
+
+    if let Some(app) = firmware.app_descriptor() {
+        details_group.add(&*LabelRow::new("Build date", &format!("{} {}", app.date, app.time)));
+        details_group.add(&*LabelRow::new("ESP-IDF version", &app.idf_version));
+    }
+
+    let verification = firmware.verify();
+    details_group.add(&*LabelRow::new("Signature", &verification.describe()));
+
+    let partitions_group = adw::PreferencesGroup::builder()
+        .title("Partitions")
+        .visible(firmware.partition_table().is_some())
+        .build();
+
+    if let Some(table) = firmware.partition_table() {
+        for partition in &table.partitions {
+            let value = format!("{:#x}, {} ({})",
+                partition.offset,
+                glib::format_size(u64::from(partition.size)),
+                partition.subtype_name());
+
+            partitions_group.add(&*LabelRow::new(&partition.label, &value));
+        }
+    }
+
+    let notes_group = adw::PreferencesGroup::builder()
+        .title("Release notes")
+        .visible(firmware.release_notes().is_some())
+        .build();
+
+    if let Some(notes) = firmware.release_notes() {
+        notes_group.add(&gtk::Label::builder()
+            .label(notes)
+            .wrap(true)
+            .xalign(0.0)
+            .selectable(true)
+            .build());
+    }
+
     let flash_group = adw::PreferencesGroup::new();
 
+    // firmware nothing vouches for needs the user's say-so, and a broken
+    // signature is never allowed
+    let trust_row = verification.needs_confirmation().then(|| {
+        adw::SwitchRow::builder()
+            .title("Flash unverified firmware")
+            .subtitle("This firmware isn't signed by a trusted key. Only flash it if you trust where it came from.")
+            .build()
+    });
+
     let flash_button = gtk::Button::builder()
         .label("Flash!")
+        .sensitive(verification.is_allowed() && !verification.needs_confirmation())
         .build();
 
     flash_button.connect_clicked({
@@ -163,18 +463,51 @@ fn review_firmware_page(ctx: UpdateContext, firmware: Arc<Firmware>) -> adw::Nav
             let Some(nav) = ctx.nav.upgrade() else { return };
 
             nav.pop();
-            nav.push(&flash_page(ctx.clone(), firmware.clone()));
+            nav.push(&flash_page(ctx.clone(), firmware.clone(), ctx.flash_options()));
         }
     });
 
+    // offer to finish off a flash of this firmware that was interrupted
+    let resume_button = can_resume(&ctx, &firmware)
+        .then(|| resume_button(ctx.clone(), firmware.clone()));
+
+    if let Some(trust_row) = &trust_row {
+        if let Some(button) = &resume_button {
+            button.set_sensitive(false);
+        }
+
+        trust_row.connect_active_notify({
+            let flash_button = flash_button.clone();
+            let resume_button = resume_button.clone();
+            move |row| {
+                flash_button.set_sensitive(row.is_active());
+
+                if let Some(button) = &resume_button {
+                    button.set_sensitive(row.is_active());
+                }
+            }
+        });
+
+        flash_group.add(trust_row);
+    }
+
     flash_group.add(&flash_button);
 
+    if let Some(button) = &resume_button {
+        flash_group.add(button);
+    }
+
     let page = adw::PreferencesPage::builder()
         .title("Review Firmware")
         .build();
 
+    let advanced_group = advanced_group(&ctx);
+
     page.add(&intro_group);
     page.add(&details_group);
+    page.add(&notes_group);
+    page.add(&partitions_group);
+    page.add(&advanced_group);
     page.add(&flash_group);
 
     NavPageBuilder::clamped(&page)
@@ -182,7 +515,90 @@ fn review_firmware_page(ctx: UpdateContext, firmware: Arc<Firmware>) -> adw::Nav
         .build()
 }
 
-fn flash_page(ctx: UpdateContext, firmware: Arc<Firmware>) -> adw::NavigationPage {
+fn advanced_group(ctx: &UpdateContext) -> adw::PreferencesGroup {
+    let group = adw::PreferencesGroup::builder()
+        .title("Advanced")
+        .build();
+
+    let rates = flash::BAUD_RATES.map(|baud| baud.to_string());
+    let rates = rates.iter().map(String::as_str).collect::<Vec<_>>();
+
+    let selected = flash::BAUD_RATES.iter()
+        .position(|&baud| baud == ctx.baud.get())
+        .unwrap_or_default();
+
+    let baud_row = adw::ComboRow::builder()
+        .title("Baud rate")
+        .subtitle("Try a slower rate if flashing fails through a USB hub or in a virtual machine")
+        .model(&gtk::StringList::new(&rates))
+        .selected(selected as u32)
+        .build();
+
+    baud_row.connect_selected_notify({
+        let baud = ctx.baud.clone();
+        move |row| {
+            if let Some(&rate) = flash::BAUD_RATES.get(row.selected() as usize) {
+                baud.set(rate);
+            }
+        }
+    });
+
+    group.add(&baud_row);
+    group
+}
+
+fn can_resume(ctx: &UpdateContext, firmware: &Firmware) -> bool {
+    match Journal::load(ctx.target.params()) {
+        Ok(journal) => journal.is_some_and(|journal| journal.matches(firmware)),
+        Err(error) => {
+            log::warn!("reading flash journal: {error}");
+            false
+        }
+    }
+}
+
+fn resume_button(ctx: UpdateContext, firmware: Arc<Firmware>) -> gtk::Button {
+    let button = gtk::Button::builder()
+        .label("Resume flash")
+        .build();
+
+    button.connect_clicked(move |_| {
+        let Some(nav) = ctx.nav.upgrade() else { return };
+
+        let options = FlashOptions {
+            resume: true,
+            ..ctx.flash_options()
+        };
+
+        nav.pop();
+        nav.push(&flash_page(ctx.clone(), firmware.clone(), options));
+    });
+
+    button
+}
+
+fn force_button(ctx: UpdateContext, firmware: Arc<Firmware>) -> gtk::Button {
+    let button = gtk::Button::builder()
+        .label("Flash anyway")
+        .css_classes(["destructive-action"])
+        .build();
+
+    button.connect_clicked(move |_| {
+        let Some(nav) = ctx.nav.upgrade() else { return };
+
+        let options = FlashOptions {
+            force: true,
+            ..ctx.flash_options()
+        };
+
+        nav.pop();
+        nav.push(&flash_page(ctx.clone(), firmware.clone(), options));
+    });
+
+    button
+}
+
+fn flash_page(ctx: UpdateContext, firmware: Arc<Firmware>, options: FlashOptions) -> adw::NavigationPage {
     let box_ = gtk::Box::builder()
         .orientation(Orientation::Vertical)
         .valign(Align::Center)
@@ -199,8 +615,19 @@ fn flash_page(ctx: UpdateContext, firmware: Arc<Firmware>) -> adw::NavigationPag
     let status_label = gtk::Label::builder()
         .build();
 
+    let detail_label = gtk::Label::builder()
+        .css_classes(["dim-label"])
+        .build();
+
+    let cancel_button = gtk::Button::builder()
+        .label("Cancel")
+        .halign(Align::Center)
+        .build();
+
     box_.append(&progress_bar);
     box_.append(&status_label);
+    box_.append(&detail_label);
+    box_.append(&cancel_button);
 
     let page = NavPageBuilder::clamped(&box_)
         .title("Flashing Tangara")
@@ -220,18 +647,29 @@ fn flash_page(ctx: UpdateContext, firmware: Arc<Firmware>) -> adw::NavigationPag
     // start flash now UI is built
     glib::spawn_future_local(async move {
         let (flash, task) = match &*ctx.target {
-            UpdateTarget::Device(device) => device.tangara.setup_flash(firmware).await,
-            UpdateTarget::Params(params) => flash::setup(Arc::new(params.clone()), firmware),
+            UpdateTarget::Device(device) => device.tangara.setup_flash(firmware.clone(), options).await,
+            UpdateTarget::Params(params) => flash::setup(Arc::new(params.clone()), firmware.clone(), options),
         };
 
         // spawn blocking flash task
         gtk::gio::spawn_blocking(move || task.run());
 
+        cancel_button.connect_clicked({
+            let cancel = flash.cancel.clone();
+            let status_label = status_label.clone();
+            move |button| {
+                cancel.cancel();
+                button.set_sensitive(false);
+                status_label.set_label("Cancelling...");
+            }
+        });
+
         // progress handler
         glib::spawn_future_local(flash_progress_task(
             flash.progress,
             progress_bar,
             status_label,
+            detail_label,
         ));
 
         // result channel
@@ -245,7 +683,7 @@ fn flash_page(ctx: UpdateContext, firmware: Arc<Firmware>) -> adw::NavigationPag
             };
 
             nav.pop();
-            nav.push(&complete(result));
+            nav.push(&complete(ctx, firmware, result));
 
             // hold on to locked until the flash has returned a result
             drop(locked);
@@ -259,40 +697,107 @@ async fn flash_progress_task(
     mut progress: Receiver<FlashStatus>,
     progress_bar: gtk::ProgressBar,
     status_label: gtk::Label,
+    detail_label: gtk::Label,
 ) {
-    let mut current_image = None;
+    let mut current_image = String::new();
 
     while let Some(progress) = progress.next().await {
         match progress {
-            FlashStatus::StartingFlash => {
+            FlashStatus::StartingFlash { .. } => {
                 status_label.set_label("Starting flash")
             }
-            FlashStatus::Image(image) => {
-                status_label.set_label(&format!("Writing {image}..."));
-                progress_bar.set_fraction(0.0);
-                current_image = Some(image);
+            FlashStatus::Image { index, count, name, .. } => {
+                current_image = format!("{name} ({} of {count})", index + 1);
+                status_label.set_label(&current_image);
             }
-            FlashStatus::Progress(written, total) => {
-                if total != 0 {
-                    let progress = written as f64 / total as f64;
-                    progress_bar.set_fraction(progress);
+            FlashStatus::Phase(phase) => {
+                let label = match phase {
+                    FlashPhase::Connecting => "Connecting to Tangara...".to_string(),
+                    FlashPhase::Erasing => format!("Erasing {current_image}..."),
+                    FlashPhase::Writing => format!("Writing {current_image}..."),
+                    FlashPhase::Verifying => format!("Verifying {current_image}..."),
+                    FlashPhase::Resetting => "Restarting Tangara...".to_string(),
+                };
+
+                status_label.set_label(&label);
+            }
+            FlashStatus::Progress(progress) => {
+                progress_bar.set_fraction(progress.fraction());
+
+                let mut detail = format!("{} of {}",
+                    glib::format_size(progress.written),
+                    glib::format_size(progress.total));
+
+                if let (Some(throughput), Some(eta)) = (progress.throughput, progress.eta) {
+                    detail.push_str(&format!(", {}/s, about {} left",
+                        glib::format_size(throughput as u64),
+                        format_duration(eta)));
                 }
 
-                if let Some(image) = &current_image {
-                    status_label.set_label(&format!("Writing {image}... block {written}/{total}"));
+                detail_label.set_label(&detail);
+            }
+            FlashStatus::Skipped(image) => {
+                status_label.set_label(&format!("{image} is up to date"));
+            }
+            FlashStatus::Retrying { image, .. } => {
+                status_label.set_label(&format!("Writing {image} failed, retrying..."));
+            }
+            FlashStatus::Connected { baud } => {
+                detail_label.set_label(&format!("Connected at {baud} baud"));
+            }
+            FlashStatus::Preflight(report) => {
+                if !report.passed() {
+                    detail_label.set_label(&report.summary());
                 }
             }
+            FlashStatus::Finished(summary) => {
+                progress_bar.set_fraction(1.0);
+                detail_label.set_label(&format!("Wrote {} in {}",
+                    glib::format_size(summary.bytes_written),
+                    format_duration(summary.elapsed)));
+            }
         }
     }
 }
 
-fn complete(message: Result<(), Option<FlashError>>) -> adw::NavigationPage {
+fn format_duration(duration: Duration) -> String {
+    match duration.as_secs() {
+        secs @ 0..60 => format!("{secs}s"),
+        secs => format!("{}m {}s", secs / 60, secs % 60),
+    }
+}
+
+fn complete(
+    ctx: UpdateContext,
+    firmware: Arc<Firmware>,
+    message: Result<(), Option<FlashError>>,
+) -> adw::NavigationPage {
+    let failed = message.is_err();
+    let refused = matches!(message, Err(Some(FlashError::Preflight(_))));
+
     let status_page = match message {
         Ok(()) => adw::StatusPage::builder()
             .icon_name("breeze-status-success")
             .title("Flash complete")
             .description("Please enjoy your freshly updated Tangara")
             .build(),
+        Err(Some(FlashError::Cancelled { remaining })) => adw::StatusPage::builder()
+            .icon_name("companion-computer-sadface-symbolic")
+            .title("Flash cancelled")
+            .description(format!(
+                "{} not written. Tangara may not start until flashing is finished.",
+                remaining.join(", ")))
+            .build(),
+        Err(Some(FlashError::Preflight(report))) => adw::StatusPage::builder()
+            .icon_name("companion-computer-sadface-symbolic")
+            .title("Tangara isn't ready to flash")
+            .description(format!(
+                "{}\n\nNothing was written to Tangara.",
+                report.problems.iter()
+                    .map(|problem| problem.to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n")))
+            .build(),
         Err(error) => adw::StatusPage::builder()
             .icon_name("companion-computer-sadface-symbolic")
             .title("Flash failed")
@@ -303,6 +808,16 @@ fn complete(message: Result<(), Option<FlashError>>) -> adw::NavigationPage {
             .build()
     };
 
+    if refused {
+        let button = force_button(ctx, firmware);
+        button.set_halign(Align::Center);
+        status_page.set_child(Some(&button));
+    } else if failed && can_resume(&ctx, &firmware) {
+        let button = resume_button(ctx, firmware);
+        button.set_halign(Align::Center);
+        status_page.set_child(Some(&button));
+    }
+
     NavPageBuilder::clamped(&status_page)
         .title(status_page.title().as_str())
         .header(adw::HeaderBar::builder()
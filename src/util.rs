@@ -1,3 +1,6 @@
+use std::future::Future;
+
+use futures::channel::oneshot;
 use glib::object::ObjectType;
 use glib::WeakRef;
 
@@ -6,3 +9,25 @@ pub fn weak<T: ObjectType>(object: &T) -> WeakRef<T> {
     ref_.set(Some(object));
     ref_
 }
+
+/// Runs a future on its own thread with a tokio runtime, for things like
+/// reqwest that need one. The GTK main loop isn't a tokio runtime.
+pub fn spawn_tokio<F, Fut>(f: F) -> oneshot::Receiver<Fut::Output>
+where
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future,
+    Fut::Output: Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("build tokio runtime");
+
+        let _ = tx.send(runtime.block_on(f()));
+    });
+
+    rx
+}